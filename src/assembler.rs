@@ -0,0 +1,414 @@
+// Builds deflate streams from a hand-written description, for example:
+//
+//     {"blocks": [
+//       {"type": "stored", "data": "6869", "nlen": 0},
+//       {"type": "dynamic", "tokens": [
+//         {"type": "text", "v": "abc"},
+//         {"type": "match", "length": 6, "distance": 3}
+//       ]}
+//     ]}
+//
+// Whatever the description leaves out is filled in the way a compressor would do it, and
+// whatever it spells out is written as is, even if it does not make a valid stream.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::data::{HuffmanCode, Value};
+use crate::encoder::{
+    check_code_lengths, code_table, encode_error, run_length_encode, write_symbol, BitWriter,
+};
+use crate::error::Error;
+use crate::{
+    build_huffman_codes, distance_codes, fixed_huffman_codes, length_code, Settings,
+    CODE_LENGTH_ALPHABET, DISTANCE_ALPHABET, DISTANCE_BASES, DISTANCE_EXTRAS,
+    LITERAL_LENGTH_ALPHABET,
+};
+
+#[derive(Deserialize)]
+pub struct StreamDescription {
+    pub blocks: Vec<BlockDescription>,
+}
+
+#[derive(Deserialize)]
+pub struct BlockDescription {
+    /// BFINAL, by default set only on the last block.
+    #[serde(rename = "final")]
+    pub bfinal: Option<bool>,
+    /// Overrides the BTYPE of the block type, e.g. with the reserved value 3.
+    pub btype: Option<u8>,
+    #[serde(flatten)]
+    pub ext: BlockDescriptionExt,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlockDescriptionExt {
+    Stored(StoredDescription),
+    Fixed(FixedDescription),
+    Dynamic(DynamicDescription),
+}
+
+#[derive(Deserialize)]
+pub struct StoredDescription {
+    /// Hex-encoded.
+    #[serde(default)]
+    pub data: String,
+    /// LEN, by default the length of `data`.
+    pub len: Option<u16>,
+    /// NLEN, by default the complement of LEN.
+    pub nlen: Option<u16>,
+}
+
+#[derive(Deserialize)]
+pub struct FixedDescription {
+    pub tokens: Vec<TokenDescription>,
+    /// Whether to end the tokens with an end-of-block, by default true.
+    pub eob: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DynamicDescription {
+    pub tokens: Vec<TokenDescription>,
+    /// Whether to end the tokens with an end-of-block, by default true.
+    pub eob: Option<bool>,
+    /// Literal/length code lengths (257 - 288), by default computed from the tokens.
+    pub hlits: Option<Vec<u8>>,
+    /// Distance code lengths (1 - 32), by default computed from the tokens.
+    pub hdists: Option<Vec<u8>>,
+    /// Code length code lengths in the order of the symbols 0 - 18, by default computed from
+    /// the run-length encoded `hlits` and `hdists`.
+    pub hclens: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TokenDescription {
+    Literal {
+        v: u8,
+    },
+    /// A literal for each byte of `v`.
+    Text {
+        v: String,
+    },
+    /// The distance is not checked against the window.
+    Match {
+        length: u32,
+        distance: u32,
+    },
+    Eob,
+}
+
+/// A token, resolved into symbols and extra bits.
+enum Symbol {
+    Literal(u16),
+    Match {
+        length: u16,
+        length_extra: (u32, usize),
+        distance: u8,
+        distance_extra: (u32, usize),
+    },
+}
+
+/// The symbol and the extra bits of a length or a distance: the code with the fewest extra bits
+/// whose range has it, as a compressor would pick. So length 258 is 285 in deflate, but 284 in
+/// deflate64, where 285 covers all the lengths with 16 extra bits.
+fn resolve_code(
+    value: u32,
+    codes: impl Iterator<Item = (usize, (usize, u32))>,
+) -> Option<(usize, (u32, usize))> {
+    codes
+        .filter(|(_, (extras, base))| *base <= value && value - base < 1 << extras)
+        .min_by_key(|(_, (extras, _))| *extras)
+        .map(|(code, (extras, base))| (code, (value - base, extras)))
+}
+
+fn resolve_token(
+    token: &TokenDescription,
+    out: &mut Vec<Symbol>,
+    index: usize,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 3.2.5. Compressed blocks (length and distance codes)
+    match token {
+        TokenDescription::Literal { v } => out.push(Symbol::Literal(u16::from(*v))),
+        TokenDescription::Text { v } => {
+            out.extend(v.bytes().map(|byte| Symbol::Literal(u16::from(byte))))
+        }
+        TokenDescription::Match { length, distance } => {
+            let length_codes =
+                (257..=285).map(|symbol| (symbol as usize, length_code(symbol, settings)));
+            let (length, length_extra) = resolve_code(*length, length_codes)
+                .ok_or_else(|| encode_error(index, &format!("Length {}", length)))?;
+            let distance_codes = (0..distance_codes(settings))
+                .map(|code| (code, (DISTANCE_EXTRAS[code], DISTANCE_BASES[code])));
+            let (distance, distance_extra) = resolve_code(*distance, distance_codes)
+                .ok_or_else(|| encode_error(index, &format!("Distance {}", distance)))?;
+            out.push(Symbol::Match {
+                length: length as u16,
+                length_extra,
+                distance: distance as u8,
+                distance_extra,
+            });
+        }
+        TokenDescription::Eob => out.push(Symbol::Literal(256)),
+    }
+    Ok(())
+}
+
+fn resolve_tokens(
+    tokens: &[TokenDescription],
+    eob: Option<bool>,
+    index: usize,
+    settings: &Settings,
+) -> Result<Vec<Symbol>, Error> {
+    let mut symbols = Vec::new();
+    for token in tokens {
+        resolve_token(token, &mut symbols, index, settings)?;
+    }
+    if eob.unwrap_or(true) {
+        symbols.push(Symbol::Literal(256));
+    }
+    Ok(symbols)
+}
+
+/// Huffman code lengths for the given symbol frequencies.
+fn huffman_lengths(freqs: &[usize]) -> Vec<u8> {
+    let mut lens = vec![0; freqs.len()];
+    // Leaves are the symbols, inner nodes are numbered after them
+    let mut parents = vec![0; freqs.len()];
+    let mut heap = BinaryHeap::new();
+    for (symbol, freq) in freqs.iter().enumerate() {
+        if *freq != 0 {
+            heap.push(Reverse((*freq, symbol)));
+        }
+    }
+    if heap.len() == 1 {
+        // 3.2.7. A single code is encoded using one bit, not zero bits
+        if let Some(Reverse((_, symbol))) = heap.pop() {
+            lens[symbol] = 1;
+        }
+        return lens;
+    }
+    while heap.len() > 1 {
+        let (Reverse((freq1, node1)), Reverse((freq2, node2))) = match (heap.pop(), heap.pop()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => unreachable!(),
+        };
+        let node = parents.len();
+        parents.push(0);
+        parents[node1] = node;
+        parents[node2] = node;
+        heap.push(Reverse((freq1 + freq2, node)));
+    }
+    let root = parents.len() - 1;
+    for (symbol, len) in lens.iter_mut().enumerate() {
+        if freqs[symbol] != 0 {
+            let mut node = symbol;
+            while node != root {
+                node = parents[node];
+                *len += 1;
+            }
+        }
+    }
+    lens
+}
+
+/// Huffman code lengths for the given symbol frequencies, none longer than `max_len`.
+fn limited_huffman_lengths(freqs: &[usize], max_len: u8) -> Vec<u8> {
+    // Flattening the frequencies makes the tree shallower, and once they are all equal, it is
+    // balanced
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lens = huffman_lengths(&freqs);
+        if lens.iter().all(|len| *len <= max_len) {
+            return lens;
+        }
+        for freq in &mut freqs {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+fn to_values(lens: &[u8]) -> Vec<Value<u8>> {
+    lens.iter()
+        .map(|len| Value {
+            v: *len,
+            start: 0,
+            end: 0,
+        })
+        .collect()
+}
+
+/// Drops the trailing zeros, but keeps at least `min` lengths.
+fn trim_lengths(mut lens: Vec<u8>, min: usize) -> Vec<u8> {
+    while lens.len() > min && lens.last() == Some(&0) {
+        lens.pop();
+    }
+    lens
+}
+
+fn write_symbols(
+    writer: &mut BitWriter,
+    symbols: &[Symbol],
+    hlits_codes: &[HuffmanCode<u16>],
+    hdists_codes: &[HuffmanCode<u8>],
+    index: usize,
+) -> Result<(), Error> {
+    let hlits_table = code_table(hlits_codes);
+    let hdists_table = code_table(hdists_codes);
+    for symbol in symbols {
+        match symbol {
+            Symbol::Literal(symbol) => write_symbol(writer, &hlits_table, *symbol, index)?,
+            Symbol::Match {
+                length,
+                length_extra,
+                distance,
+                distance_extra,
+            } => {
+                write_symbol(writer, &hlits_table, *length, index)?;
+                writer.write_bits(length_extra.0, length_extra.1);
+                write_symbol(writer, &hdists_table, *distance, index)?;
+                writer.write_bits(distance_extra.0, distance_extra.1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn assemble_stored(
+    writer: &mut BitWriter,
+    stored: &StoredDescription,
+    index: usize,
+) -> Result<(), Error> {
+    // 3.2.4. Non-compressed blocks (BTYPE=00)
+    let data = hex::decode(&stored.data)?;
+    let len = match stored.len {
+        Some(len) => len,
+        None => u16::try_from(data.len())
+            .map_err(|_| encode_error(index, &format!("Stored length {}", data.len())))?,
+    };
+    writer.align(&None);
+    writer.write_bits(u32::from(len), 16);
+    writer.write_bits(u32::from(stored.nlen.unwrap_or(!len)), 16);
+    writer.write_bytes(&data);
+    Ok(())
+}
+
+fn assemble_dynamic(
+    writer: &mut BitWriter,
+    dynamic: &DynamicDescription,
+    index: usize,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 3.2.7. Compression with dynamic Huffman codes (BTYPE=10)
+    let symbols = resolve_tokens(&dynamic.tokens, dynamic.eob, index, settings)?;
+    let mut hlits_freqs = vec![0; 286];
+    let mut hdists_freqs = vec![0; 32];
+    for symbol in &symbols {
+        match symbol {
+            Symbol::Literal(symbol) => hlits_freqs[*symbol as usize] += 1,
+            Symbol::Match {
+                length, distance, ..
+            } => {
+                hlits_freqs[*length as usize] += 1;
+                hdists_freqs[*distance as usize] += 1;
+            }
+        }
+    }
+    let hlits = match &dynamic.hlits {
+        Some(hlits) => hlits.clone(),
+        None => trim_lengths(limited_huffman_lengths(&hlits_freqs, 15), 257),
+    };
+    // One distance code of zero bits means that there are no distance codes used at all
+    let hdists = match &dynamic.hdists {
+        Some(hdists) => hdists.clone(),
+        None => trim_lengths(limited_huffman_lengths(&hdists_freqs, 15), 1),
+    };
+    if !(257..=288).contains(&hlits.len()) || !(1..=32).contains(&hdists.len()) {
+        return Err(encode_error(index, "Number of code lengths"));
+    }
+    check_code_lengths(&hlits, 15, index, "hlits")?;
+    check_code_lengths(&hdists, 15, index, "hdists")?;
+    // The code lengths are run-length encoded together
+    let lens = [hlits.as_slice(), hdists.as_slice()].concat();
+    let rle = match &dynamic.hclens {
+        // Only the repeat codes that the given code length code has
+        Some(hclens) if hclens.len() == 19 => {
+            run_length_encode(&lens, |symbol| hclens[symbol as usize] != 0)
+        }
+        _ => run_length_encode(&lens, |_| true),
+    };
+    let hclens = match &dynamic.hclens {
+        Some(hclens) => hclens.clone(),
+        None => {
+            let mut hclens_freqs = vec![0; 19];
+            for (symbol, _, _) in &rle {
+                hclens_freqs[*symbol as usize] += 1;
+            }
+            limited_huffman_lengths(&hclens_freqs, 7)
+        }
+    };
+    if hclens.len() != 19 {
+        return Err(encode_error(index, "Number of code length code lengths"));
+    }
+    check_code_lengths(&hclens, 7, index, "hclens")?;
+    // (HCLEN + 4) x 3 bits in the order of the code length alphabet
+    let hclens = trim_lengths(
+        CODE_LENGTH_ALPHABET
+            .iter()
+            .map(|symbol| hclens[*symbol as usize])
+            .collect(),
+        4,
+    );
+    writer.write_bits((hlits.len() - 257) as u32, 5);
+    writer.write_bits((hdists.len() - 1) as u32, 5);
+    writer.write_bits((hclens.len() - 4) as u32, 4);
+    for hclen in &hclens {
+        writer.write_bits(u32::from(*hclen), 3);
+    }
+    let hclens_codes = build_huffman_codes(&CODE_LENGTH_ALPHABET, &to_values(&hclens));
+    let hclens_table = code_table(&hclens_codes);
+    for (symbol, repeat, repeat_len) in rle {
+        write_symbol(writer, &hclens_table, symbol, index)?;
+        writer.write_bits(repeat, repeat_len);
+    }
+    write_symbols(
+        writer,
+        &symbols,
+        &build_huffman_codes(&LITERAL_LENGTH_ALPHABET, &to_values(&hlits)),
+        &build_huffman_codes(&DISTANCE_ALPHABET, &to_values(&hdists)),
+        index,
+    )
+}
+
+/// Assembles `description` into a deflate stream that starts at a byte boundary.
+pub fn assemble(description: &StreamDescription, settings: &Settings) -> Result<Vec<u8>, Error> {
+    let mut writer = BitWriter::new();
+    for (index, block) in description.blocks.iter().enumerate() {
+        // 3.2.3. Details of block format
+        let bfinal = block
+            .bfinal
+            .unwrap_or(index + 1 == description.blocks.len());
+        let btype = match &block.ext {
+            BlockDescriptionExt::Stored(_) => 0,
+            BlockDescriptionExt::Fixed(_) => 1,
+            BlockDescriptionExt::Dynamic(_) => 2,
+        };
+        writer.write_bits(u32::from(bfinal), 1);
+        writer.write_bits(u32::from(block.btype.unwrap_or(btype)), 2);
+        match &block.ext {
+            BlockDescriptionExt::Stored(stored) => assemble_stored(&mut writer, stored, index)?,
+            BlockDescriptionExt::Fixed(fixed) => {
+                let symbols = resolve_tokens(&fixed.tokens, fixed.eob, index, settings)?;
+                let (hlits_codes, hdists_codes) = fixed_huffman_codes(0);
+                write_symbols(&mut writer, &symbols, &hlits_codes, &hdists_codes, index)?
+            }
+            BlockDescriptionExt::Dynamic(dynamic) => {
+                assemble_dynamic(&mut writer, dynamic, index, settings)?
+            }
+        }
+    }
+    writer.align(&None);
+    Ok(writer.bytes)
+}
@@ -1,16 +1,26 @@
 extern crate clap;
 extern crate deflate_parser;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 
 use std::fs::File;
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::process;
 
 use clap::Parser;
 
-use deflate_parser::data::{CompressedStream, DeflateStream, ZlibStream};
+use deflate_parser::assembler::{assemble, StreamDescription};
+use deflate_parser::data::{
+    CompressedStream, DeflateBlock, DeflateStream, ScanResult, WebSocketSession, ZlibStream,
+};
 use deflate_parser::error::Error;
+use deflate_parser::visitor::{ContainerHeader, ParseVisitor};
 use deflate_parser::Window;
-use deflate_parser::{parse, write_data, Settings};
+use deflate_parser::{
+    parse, parse_reader, parse_streaming, write_block_data, write_data, Settings,
+};
 use std::io::BufWriter;
 
 #[derive(Parser)]
@@ -37,28 +47,133 @@ struct Args {
     #[arg(long)]
     zlib: bool,
 
+    #[arg(long)]
+    zip: bool,
+
+    #[arg(long)]
+    png: bool,
+
+    #[arg(long)]
+    pdf: bool,
+
+    #[arg(long)]
+    git: bool,
+
+    #[arg(long)]
+    websocket: bool,
+
+    #[arg(long)]
+    no_context_takeover: bool,
+
+    #[arg(long)]
+    scan: bool,
+
+    #[arg(long)]
+    scan_bits: bool,
+
+    #[arg(long, default_value_t = 64)]
+    min_plain_len: usize,
+
     #[arg(long)]
     data: bool,
 
+    #[arg(long)]
+    deflate64: bool,
+
+    #[arg(long)]
+    stream: bool,
+
+    #[arg(long)]
+    assemble: bool,
+
     file: String,
 }
 
+#[derive(Serialize)]
+struct OutputWithError<'a> {
+    #[serde(flatten)]
+    stream: &'a Option<CompressedStream>,
+    error: &'a Error,
+}
+
+struct BlockPrinter<'a, W: Write> {
+    output: &'a mut W,
+    extract: Option<&'a mut File>,
+    extracted: bool,
+}
+
+impl<W: Write> ParseVisitor for BlockPrinter<'_, W> {
+    fn on_container_header(&mut self, header: ContainerHeader) -> Result<(), Error> {
+        // Stored zip entries have no blocks, so zip archives are extracted from the model
+        if let ContainerHeader::Zip(_) = header {
+            self.extract = None;
+        }
+        Ok(())
+    }
+
+    fn on_block_end(&mut self, _index: usize, block: &DeflateBlock) -> Result<(), Error> {
+        serde_json::to_writer(&mut *self.output, block)?;
+        self.output.write_all(b"\n")?;
+        if let Some(f) = &mut self.extract {
+            write_block_data(f, block)?;
+            self.extracted = true;
+        }
+        Ok(())
+    }
+}
+
 fn main() -> Result<(), Error> {
     let args = Args::parse();
     let settings = Settings {
         bit_offset: args.bit_offset,
         data: args.data || args.extract.is_some(),
+        deflate64: args.deflate64,
     };
     let output: Box<dyn std::io::Write> = match args.output {
         Some(output_path) => Box::new(std::fs::File::create(output_path)?),
         None => Box::new(std::io::stdout()),
     };
+    let mut output = BufWriter::new(output);
+    if args.assemble {
+        // The file is a JSON description of a deflate stream, which is written to the output
+        let description: StreamDescription = if args.file == "-" {
+            serde_json::from_reader(io::stdin().lock())?
+        } else {
+            serde_json::from_reader(File::open(&args.file)?)?
+        };
+        output.write_all(&assemble(&description, &settings)?)?;
+        output.flush()?;
+        return Ok(());
+    }
+    let mut extract = match &args.extract {
+        Some(extract) => Some(File::create(extract)?),
+        None => None,
+    };
     let mut stream: Option<CompressedStream> = if args.raw {
         Some(CompressedStream::Raw(DeflateStream::default()))
     } else if args.raw_dht {
         Some(CompressedStream::Dht(Box::default()))
     } else if args.zlib {
         Some(CompressedStream::Zlib(ZlibStream::default()))
+    } else if args.zip {
+        Some(CompressedStream::Zip(Box::default()))
+    } else if args.png {
+        Some(CompressedStream::Png(Box::default()))
+    } else if args.pdf {
+        Some(CompressedStream::Pdf(Box::default()))
+    } else if args.git {
+        Some(CompressedStream::Git(Box::default()))
+    } else if args.websocket {
+        Some(CompressedStream::WebSocket(Box::new(WebSocketSession {
+            no_context_takeover: args.no_context_takeover,
+            messages: Vec::new(),
+        })))
+    } else if args.scan || args.scan_bits {
+        Some(CompressedStream::Scan(Box::new(ScanResult {
+            bit_offsets: args.scan_bits,
+            min_plain_len: args.min_plain_len,
+            hits: Vec::new(),
+        })))
     } else {
         None
     };
@@ -66,17 +181,104 @@ fn main() -> Result<(), Error> {
     if let Some(dictionary) = args.dictionary {
         window.append_dictionary_from_file(&mut File::open(dictionary)?)?;
     }
-    let result = parse(&mut stream, Path::new(&args.file), &mut window, &settings);
-    serde_json::to_writer_pretty(BufWriter::new(output), &stream)?;
-    match result {
-        Ok(()) => {}
+    let mut extracted = false;
+    let result = if args.stream {
+        // Each block is printed on its own line as soon as it is decoded, followed by the rest
+        // of the stream
+        let reader: Box<dyn Read> = if args.file == "-" {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(File::open(&args.file)?)
+        };
+        let mut printer = BlockPrinter {
+            output: &mut output,
+            extract: extract.as_mut(),
+            extracted: false,
+        };
+        let result = parse_streaming(&mut stream, reader, &mut window, &settings, &mut printer);
+        extracted = printer.extracted;
+        result
+    } else if args.file == "-" {
+        parse_reader(&mut stream, &mut io::stdin().lock(), &mut window, &settings)
+    } else {
+        parse(&mut stream, Path::new(&args.file), &mut window, &settings)
+    };
+    match &result {
+        Ok(()) => write_json(&mut output, &stream, args.stream)?,
         Err(err) => {
-            serde_json::to_string_pretty(&err)?;
+            print_error(err);
+            let output_with_error = OutputWithError {
+                stream: &stream,
+                error: err,
+            };
+            write_json(&mut output, &output_with_error, args.stream)?
         }
     }
-    if let Some(extract) = &args.extract {
-        let mut f = File::create(extract)?;
-        write_data(&mut f, &stream)?;
+    output.flush()?;
+    // Unless the blocks were already extracted as they were decoded
+    if let (Some(f), false) = (&mut extract, extracted) {
+        write_data(f, &stream)?;
+    }
+    if result.is_err() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn write_json<W: Write, T: serde::Serialize>(
+    output: &mut W,
+    value: &T,
+    line: bool,
+) -> Result<(), Error> {
+    if line {
+        serde_json::to_writer(&mut *output, value)?;
+        output.write_all(b"\n")?;
+    } else {
+        serde_json::to_writer_pretty(output, value)?;
     }
     Ok(())
 }
+
+fn print_error(err: &Error) {
+    let err = match err {
+        Error::Parse(err) => err,
+        _ => {
+            eprintln!("error: {}", err);
+            return;
+        }
+    };
+    eprintln!(
+        "error: {} at bit {} (byte {}, bit {})",
+        err.msg,
+        err.pos,
+        err.pos / 8,
+        err.pos % 8
+    );
+    let context = match &err.context {
+        Some(context) => context,
+        None => return,
+    };
+    if let (Some(index), Some(start)) = (context.block, context.block_start) {
+        let btype = match context.btype {
+            Some(btype) => format!(" (BTYPE={})", btype),
+            None => String::new(),
+        };
+        eprintln!(
+            "while parsing block {}{} that starts at bit {} (byte {}, bit {})",
+            index,
+            btype,
+            start,
+            start / 8,
+            start % 8
+        );
+    }
+    if let Some(field) = &context.field {
+        match context.symbol {
+            Some(symbol) => eprintln!("in field {} (symbol={})", field, symbol),
+            None => eprintln!("in field {}", field),
+        }
+    }
+    if let Some(plain_pos) = context.plain_pos {
+        eprintln!("at plain offset {}", plain_pos);
+    }
+}
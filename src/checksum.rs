@@ -0,0 +1,80 @@
+const fn make_crc32_table() -> [u32; 256] {
+    // RFC 1952 8. Appendix: Sample CRC Code
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32 { crc: 0xffffffff }
+    }
+}
+
+impl Crc32 {
+    pub fn update_byte(&mut self, byte: u8) {
+        self.crc = CRC32_TABLE[((self.crc ^ u32::from(byte)) & 0xff) as usize] ^ (self.crc >> 8);
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.update_byte(*byte);
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.crc ^ 0xffffffff
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc32 = Crc32::default();
+    crc32.update(bytes);
+    crc32.value()
+}
+
+#[derive(Clone, Copy)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    pub fn update_byte(&mut self, byte: u8) {
+        // RFC 1950 9. Appendix: Sample code
+        const BASE: u32 = 65521;
+        self.a = (self.a + u32::from(byte)) % BASE;
+        self.b = (self.b + self.a) % BASE;
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
@@ -1,13 +1,58 @@
-#[derive(Serialize)]
-#[serde(untagged)]
+use crate::error::ParseError;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum CompressedStream {
     Raw(DeflateStream),
-    Gzip(Box<GzipStream>),
+    Gzip(GzipFile),
     Dht(Box<DynamicHuffmanTable>),
     Zlib(ZlibStream),
+    Zip(Box<ZipArchive>),
+    Png(Box<PngImage>),
+    Pdf(Box<PdfDocument>),
+    Git(Box<GitFile>),
+    WebSocket(Box<WebSocketSession>),
+    Detected(Box<DetectedStream>),
+    Scan(Box<ScanResult>),
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ScanResult {
+    pub bit_offsets: bool,
+    pub min_plain_len: usize,
+    pub hits: Vec<ScanHit>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScanHit {
+    pub offset: usize,
+    pub end: usize,
+    pub format: String,
+    pub compressed_len: usize,
+    pub plain_len: usize,
+    pub stream: CompressedStream,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DetectedStream {
+    pub format: String,
+    pub reason: String,
+    pub stream: CompressedStream,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GzipFile {
+    pub members: Vec<GzipStream>,
+    pub bgzf_offsets: Option<Vec<BgzfOffset>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct BgzfOffset {
+    pub compressed: usize,
+    pub uncompressed: usize,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct GzipStream {
     pub magic: Value<u16>,
     pub method: Option<Value<u8>>,
@@ -15,27 +60,311 @@ pub struct GzipStream {
     pub time: Option<Value<u32>>,
     pub xflags: Option<Value<u8>>,
     pub os: Option<Value<u8>>,
-    pub name: Option<Value<String>>,
+    pub extra: Option<GzipExtra>,
+    pub name: Option<Value<Latin1String>>,
+    pub comment: Option<Value<Latin1String>>,
+    pub header_crc: Option<Value<u16>>,
+    pub header_crc_computed: Option<u16>,
     pub deflate: Option<DeflateStream>,
     pub checksum: Option<Value<u32>>,
+    pub checksum_computed: Option<u32>,
     pub len: Option<Value<u32>>,
+    pub len_computed: Option<u32>,
+    pub bgzf: Option<BgzfBlock>,
+    pub warnings: Vec<ParseError>,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct BgzfBlock {
+    pub bsize: Value<u16>,
+    pub bsize_computed: Option<usize>,
+    pub eof: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GzipExtra {
+    pub xlen: Option<Value<u16>>,
+    pub subfields: Vec<GzipExtraSubfield>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GzipExtraSubfield {
+    pub si1: Option<Value<u8>>,
+    pub si2: Option<Value<u8>>,
+    pub len: Option<Value<u16>>,
+    pub data: Option<Value<String>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct ZlibStream {
     pub cmf: Option<Value<u8>>,
     pub flg: Option<Value<u8>>,
     pub dictid: Option<Value<u32>>,
+    pub dictid_computed: Option<u32>,
     pub deflate: Option<DeflateStream>,
     pub adler32: Option<Value<u32>>,
+    pub adler32_computed: Option<u32>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ZipArchive {
+    pub entries: Vec<ZipEntry>,
+    pub central_directory: Vec<ZipCentralEntry>,
+    pub zip64_end_of_central_directory: Option<Zip64EndOfCentralDirectory>,
+    pub zip64_end_of_central_directory_locator: Option<Zip64EndOfCentralDirectoryLocator>,
+    pub end_of_central_directory: Option<ZipEndOfCentralDirectory>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ZipEntry {
+    pub offset: usize,
+    pub signature: Option<Value<u32>>,
+    pub version: Option<Value<u16>>,
+    pub flags: Option<Value<u16>>,
+    pub method: Option<Value<u16>>,
+    pub time: Option<Value<u16>>,
+    pub date: Option<Value<u16>>,
+    pub crc32: Option<Value<u32>>,
+    pub compressed_size: Option<Value<u32>>,
+    pub uncompressed_size: Option<Value<u32>>,
+    pub name_len: Option<Value<u16>>,
+    pub extra_len: Option<Value<u16>>,
+    pub name: Option<Value<Latin1String>>,
+    pub extra: Option<Value<String>>,
+    pub zip64: Option<Zip64ExtraField>,
+    pub deflate: Option<DeflateStream>,
+    pub stored: Option<Value<String>>,
+    pub data_descriptor: Option<ZipDataDescriptor>,
+    pub crc32_computed: Option<u32>,
+    pub compressed_size_computed: Option<usize>,
+    pub uncompressed_size_computed: Option<usize>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ZipDataDescriptor {
+    pub signature: Option<Value<u32>>,
+    pub crc32: Option<Value<u32>>,
+    pub compressed_size: Option<Value<u64>>,
+    pub uncompressed_size: Option<Value<u64>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Zip64ExtraField {
+    pub uncompressed_size: Option<Value<u64>>,
+    pub compressed_size: Option<Value<u64>>,
+    pub local_header_offset: Option<Value<u64>>,
+    pub disk_start: Option<Value<u32>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ZipCentralEntry {
+    pub offset: usize,
+    pub signature: Option<Value<u32>>,
+    pub version_made_by: Option<Value<u16>>,
+    pub version: Option<Value<u16>>,
+    pub flags: Option<Value<u16>>,
+    pub method: Option<Value<u16>>,
+    pub time: Option<Value<u16>>,
+    pub date: Option<Value<u16>>,
+    pub crc32: Option<Value<u32>>,
+    pub compressed_size: Option<Value<u32>>,
+    pub uncompressed_size: Option<Value<u32>>,
+    pub name_len: Option<Value<u16>>,
+    pub extra_len: Option<Value<u16>>,
+    pub comment_len: Option<Value<u16>>,
+    pub disk_start: Option<Value<u16>>,
+    pub internal_attributes: Option<Value<u16>>,
+    pub external_attributes: Option<Value<u32>>,
+    pub local_header_offset: Option<Value<u32>>,
+    pub name: Option<Value<Latin1String>>,
+    pub extra: Option<Value<String>>,
+    pub comment: Option<Value<Latin1String>>,
+    pub zip64: Option<Zip64ExtraField>,
+    pub local_entry: Option<usize>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Zip64EndOfCentralDirectory {
+    pub offset: usize,
+    pub signature: Option<Value<u32>>,
+    pub size: Option<Value<u64>>,
+    pub version_made_by: Option<Value<u16>>,
+    pub version: Option<Value<u16>>,
+    pub disk: Option<Value<u32>>,
+    pub central_directory_disk: Option<Value<u32>>,
+    pub disk_entries: Option<Value<u64>>,
+    pub entries: Option<Value<u64>>,
+    pub central_directory_size: Option<Value<u64>>,
+    pub central_directory_offset: Option<Value<u64>>,
+    pub extensible_data: Option<Value<String>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Zip64EndOfCentralDirectoryLocator {
+    pub offset: usize,
+    pub signature: Option<Value<u32>>,
+    pub zip64_end_of_central_directory_disk: Option<Value<u32>>,
+    pub zip64_end_of_central_directory_offset: Option<Value<u64>>,
+    pub disks: Option<Value<u32>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ZipEndOfCentralDirectory {
+    pub offset: usize,
+    pub signature: Option<Value<u32>>,
+    pub disk: Option<Value<u16>>,
+    pub central_directory_disk: Option<Value<u16>>,
+    pub disk_entries: Option<Value<u16>>,
+    pub entries: Option<Value<u16>>,
+    pub central_directory_size: Option<Value<u32>>,
+    pub central_directory_offset: Option<Value<u32>>,
+    pub comment_len: Option<Value<u16>>,
+    pub comment: Option<Value<Latin1String>>,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Default, Serialize, Deserialize)]
+pub struct PngImage {
+    pub signature: Option<Value<String>>,
+    pub chunks: Vec<PngChunk>,
+    pub header: Option<PngHeader>,
+    pub streams: Vec<PngStream>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PngChunk {
+    pub offset: usize,
+    pub length: Option<Value<u32>>,
+    pub chunk_type: Option<Value<String>>,
+    pub sequence_number: Option<Value<u32>>,
+    pub crc: Option<Value<u32>>,
+    pub crc_computed: Option<u32>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PngHeader {
+    pub width: Option<Value<u32>>,
+    pub height: Option<Value<u32>>,
+    pub bit_depth: Option<Value<u8>>,
+    pub color_type: Option<Value<u8>>,
+    pub compression_method: Option<Value<u8>>,
+    pub filter_method: Option<Value<u8>>,
+    pub interlace_method: Option<Value<u8>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PngStream {
+    pub chunks: Vec<usize>,
+    pub width: u32,
+    pub height: u32,
+    pub zlib: ZlibStream,
+    pub scanlines: Vec<PngScanline>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PngScanline {
+    pub pass: u8,
+    pub row: u32,
+    pub plain_pos: usize,
+    pub filter: Option<u8>,
+    pub matched_scanlines: Vec<usize>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct PdfDocument {
+    pub objects: Vec<PdfObject>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PdfObject {
+    pub number: u32,
+    pub generation: u16,
+    pub offset: usize,
+    pub filters: Vec<String>,
+    pub length: Option<usize>,
+    pub stream: Option<Value<usize>>,
+    pub zlib: Option<ZlibStream>,
+    pub error: Option<ParseError>,
+    pub predictor: Option<PdfPredictor>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PdfPredictor {
+    pub predictor: u32,
+    pub colors: u32,
+    pub bits_per_component: u32,
+    pub columns: u32,
+    pub rows: Vec<PdfPredictorRow>,
+    pub data: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PdfPredictorRow {
+    pub plain_pos: usize,
+    pub filter: u8,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct GitFile {
+    pub signature: Option<Value<u32>>,
+    pub version: Option<Value<u32>>,
+    pub count: Option<Value<u32>>,
+    pub objects: Vec<GitObject>,
+    pub checksum: Option<Value<String>>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GitObject {
+    pub offset: usize,
+    pub object_type: Option<Value<u8>>,
+    pub type_name: Option<String>,
+    pub size: Option<Value<u64>>,
+    pub base_offset: Option<Value<u64>>,
+    pub base_offset_computed: Option<usize>,
+    pub base_name: Option<Value<String>>,
+    pub header: Option<String>,
+    pub zlib: Option<ZlibStream>,
+    pub end: Option<usize>,
+    pub size_computed: Option<usize>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct WebSocketSession {
+    pub no_context_takeover: bool,
+    pub messages: Vec<WebSocketMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebSocketMessage {
+    pub path: Option<String>,
+    pub length: Option<Value<u32>>,
+    pub offset: usize,
+    pub deflate: DeflateStream,
+    // The block that ends in the 00 00 ff ff trailer, which the parser appends to the payload.
+    // Its positions past the payload are synthetic and do not refer to the file.
+    pub trailer_block: Option<usize>,
+    pub plain_start: usize,
+    pub plain_end: Option<usize>,
+    pub warnings: Vec<ParseError>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct DeflateStream {
     pub blocks: Vec<DeflateBlock>,
+    // The bits after the last block up to the byte boundary
+    pub padding: Option<Value<u8>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DeflateBlock {
     pub header: DeflateBlockHeader,
     pub end: Option<usize>,
@@ -45,27 +374,30 @@ pub struct DeflateBlock {
     pub ext: Option<DeflateBlockExt>,
 }
 
-#[derive(Serialize)]
-#[serde(untagged)]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum DeflateBlockExt {
     Stored(DeflateBlockStored),
     Fixed(DeflateBlockFixed),
     Dynamic(Box<DeflateBlockDynamic>),
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DeflateBlockStored {
+    // The bits after the block header up to the byte boundary
+    pub padding: Option<Value<u8>>,
     pub len: Option<Value<u16>>,
     pub nlen: Option<Value<u16>>,
     pub data: Option<Value<String>>,
+    pub warnings: Vec<ParseError>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DeflateBlockFixed {
     pub tokens: Option<Vec<Value<Token>>>,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct DynamicHuffmanTable {
     pub hlit: Option<Value<u8>>,
     pub hdist: Option<Value<u8>>,
@@ -74,6 +406,8 @@ pub struct DynamicHuffmanTable {
     pub hclens_codes: Option<Vec<HuffmanCode<u8>>>,
     pub hclens_tree: Option<HuffmanTree<u8>>,
     pub hlits: Option<Vec<Value<u8>>>,
+    // The code length codes that `hlits` was decoded from
+    pub hlits_rle: Option<Vec<CodeLengthCode>>,
     pub hlits_codes: Option<Vec<HuffmanCode<u16>>>,
     pub hlits_tree: Option<HuffmanTree<u16>>,
     pub hdists: Option<Vec<Value<u8>>>,
@@ -81,26 +415,33 @@ pub struct DynamicHuffmanTable {
     pub hdists_tree: Option<HuffmanTree<u8>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct CodeLengthCode {
+    pub symbol: Value<u8>,
+    // The extra bits of the repeat codes 16, 17 and 18
+    pub repeat: Option<Value<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct DeflateBlockDynamic {
     pub dht: Option<DynamicHuffmanTable>,
     pub tokens: Option<Vec<Value<Token>>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct DeflateBlockHeader {
     pub bfinal: Option<Value<u8>>,
     pub btype: Option<Value<u8>>,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Value<T: Clone> {
     pub v: T,
     pub start: usize,
     pub end: usize, // non-inclusive
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct HuffmanCode<T> {
     pub symbol: T,
     pub code: u16,
@@ -108,14 +449,20 @@ pub struct HuffmanCode<T> {
     pub bin: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum HuffmanTree<T> {
     Node(Box<[HuffmanTree<T>; 2]>),
     Leaf(Option<T>),
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Latin1String {
+    pub text: String,
+    pub hex: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LiteralToken {
     pub plain_pos: usize,
     pub v: u8,
@@ -123,25 +470,25 @@ pub struct LiteralToken {
     pub hex: String,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EobToken {
     pub plain_pos: usize,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WindowToken {
     pub plain_pos: usize,
     pub length: Value<u16>,
-    pub length_extra: Value<u8>,
-    pub length_value: u16,
+    pub length_extra: Value<u16>,
+    pub length_value: u32,
     pub distance: Value<u8>,
     pub distance_extra: Value<u16>,
-    pub distance_value: u16,
+    pub distance_value: u32,
     pub hex: String,
 }
 
-#[derive(Clone, Serialize)]
-#[serde(untagged)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Token {
     Literal(LiteralToken),
     Eob(EobToken),
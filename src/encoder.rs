@@ -0,0 +1,357 @@
+// Writes a parsed deflate stream back into bits. The encoder makes no decisions of its own: the
+// block headers, the code lengths, their run-length encoding, the tokens and the padding all
+// come from the model, so an unmodified model produces exactly the bits it was parsed from.
+
+use crate::data::{
+    CodeLengthCode, DeflateBlock, DeflateBlockExt, DeflateBlockStored, DeflateStream,
+    DynamicHuffmanTable, HuffmanCode, Token, Value,
+};
+use crate::error::Error;
+use crate::{
+    build_huffman_codes, fixed_huffman_codes, length_code, Settings, CODE_LENGTH_ALPHABET,
+    DISTANCE_ALPHABET, DISTANCE_EXTRAS, LITERAL_LENGTH_ALPHABET,
+};
+
+pub(crate) struct BitWriter {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) pos: usize,
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: u32) {
+        if self.pos.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        self.bytes[self.pos / 8] |= ((bit & 1) as u8) << (self.pos % 8);
+        self.pos += 1;
+    }
+
+    /// Writes the `n` low bits of `v`, starting with the least-significant one.
+    pub(crate) fn write_bits(&mut self, v: u32, n: usize) {
+        for i in 0..n {
+            self.write_bit(v >> i);
+        }
+    }
+
+    /// Writes a Huffman code, starting with the most-significant bit.
+    pub(crate) fn write_code<T>(&mut self, code: &HuffmanCode<T>) {
+        for i in (0..code.len.v).rev() {
+            self.write_bit(u32::from(code.code) >> i);
+        }
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_bits(u32::from(*byte), 8);
+        }
+    }
+
+    /// Writes `padding` up to the byte boundary, or zeros if it is missing.
+    pub(crate) fn align(&mut self, padding: &Option<Value<u8>>) {
+        let n = (8 - (self.pos & 7)) & 7;
+        self.write_bits(padding.as_ref().map_or(0, |x| u32::from(x.v)), n);
+    }
+}
+
+pub(crate) fn encode_error(index: usize, msg: &str) -> Error {
+    Error::Encode(format!("Block {}: {}", index, msg))
+}
+
+/// Fails on code lengths that do not fit their field: 15 for the literal/length and distance
+/// codes, 7 for the code length codes.
+pub(crate) fn check_code_lengths(
+    lens: &[u8],
+    max_len: u8,
+    index: usize,
+    name: &str,
+) -> Result<(), Error> {
+    match lens.iter().find(|len| **len > max_len) {
+        Some(len) => Err(encode_error(
+            index,
+            &format!("Code length {} in {} (max={})", len, name, max_len),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn field<'a, T>(value: &'a Option<T>, index: usize, name: &str) -> Result<&'a T, Error> {
+    value
+        .as_ref()
+        .ok_or_else(|| encode_error(index, &format!("Missing {}", name)))
+}
+
+fn write_field<T: Clone + Into<u32>>(
+    writer: &mut BitWriter,
+    value: &Option<Value<T>>,
+    n: usize,
+    index: usize,
+    name: &str,
+) -> Result<(), Error> {
+    let value = field(value, index, name)?;
+    writer.write_bits(value.v.clone().into(), n);
+    Ok(())
+}
+
+pub(crate) fn write_symbol<T: Clone + Into<usize>>(
+    writer: &mut BitWriter,
+    codes: &[Option<&HuffmanCode<T>>],
+    symbol: T,
+    index: usize,
+) -> Result<(), Error> {
+    let symbol = symbol.into();
+    match codes.get(symbol) {
+        Some(Some(code)) => {
+            writer.write_code(code);
+            Ok(())
+        }
+        _ => Err(encode_error(
+            index,
+            &format!("No code for symbol {}", symbol),
+        )),
+    }
+}
+
+/// Indexes the codes by their symbols.
+pub(crate) fn code_table<T: Clone + Into<usize>>(
+    codes: &[HuffmanCode<T>],
+) -> Vec<Option<&HuffmanCode<T>>> {
+    let mut table = Vec::new();
+    for code in codes {
+        let symbol = code.symbol.clone().into();
+        if table.len() <= symbol {
+            table.resize(symbol + 1, None);
+        }
+        table[symbol] = Some(code);
+    }
+    table
+}
+
+fn encode_stored(
+    writer: &mut BitWriter,
+    stored: &DeflateBlockStored,
+    index: usize,
+) -> Result<(), Error> {
+    // 3.2.4. Non-compressed blocks (BTYPE=00)
+    writer.align(&stored.padding);
+    write_field(writer, &stored.len, 16, index, "len")?;
+    write_field(writer, &stored.nlen, 16, index, "nlen")?;
+    let data = field(&stored.data, index, "data")?;
+    writer.write_bytes(&hex::decode(&data.v)?);
+    Ok(())
+}
+
+/// The code length codes that store `lens`: a symbol and its extra bits. The repeat codes are
+/// used only if `has_code` says that the code length code has them.
+pub(crate) fn run_length_encode(
+    lens: &[u8],
+    has_code: impl Fn(u8) -> bool,
+) -> Vec<(u8, u32, usize)> {
+    // 3.2.7. Compression with dynamic Huffman codes (BTYPE=10)
+    let mut codes = Vec::new();
+    let mut i = 0;
+    while i < lens.len() {
+        let len = lens[i];
+        let run = lens[i..].iter().take_while(|x| **x == len).count();
+        if len == 0 && run >= 11 && has_code(18) {
+            // 18: Repeat a code length of 0 for 11 - 138 times
+            let repeat = run.min(138);
+            codes.push((18, (repeat - 11) as u32, 7));
+            i += repeat;
+        } else if len == 0 && run >= 3 && has_code(17) {
+            // 17: Repeat a code length of 0 for 3 - 10 times
+            let repeat = run.min(10);
+            codes.push((17, (repeat - 3) as u32, 3));
+            i += repeat;
+        } else if len != 0 && run >= 4 && has_code(16) {
+            codes.push((len, 0, 0));
+            // 16: Copy the previous code length 3 - 6 times
+            let mut left = run - 1;
+            while left >= 3 {
+                let repeat = left.min(6);
+                codes.push((16, (repeat - 3) as u32, 2));
+                left -= repeat;
+            }
+            i += run - left;
+        } else {
+            codes.push((len, 0, 0));
+            i += 1;
+        }
+    }
+    codes
+}
+
+/// The code lengths that `rle` stores, or `None` if it is not valid.
+fn run_length_decode(rle: &[CodeLengthCode]) -> Option<Vec<u8>> {
+    let mut lens = Vec::new();
+    for code in rle {
+        let repeat = || code.repeat.as_ref().map(|x| x.v as usize);
+        match code.symbol.v {
+            0..=15 => lens.push(code.symbol.v),
+            16 => {
+                let last = *lens.last()?;
+                lens.extend(std::iter::repeat_n(last, 3 + repeat()?));
+            }
+            17 => lens.extend(std::iter::repeat_n(0, 3 + repeat()?)),
+            18 => lens.extend(std::iter::repeat_n(0, 11 + repeat()?)),
+            _ => return None,
+        }
+    }
+    Some(lens)
+}
+
+fn encode_code_lengths(
+    writer: &mut BitWriter,
+    dht: &DynamicHuffmanTable,
+    hlits: &[u8],
+    index: usize,
+) -> Result<(), Error> {
+    // 3.2.7. Compression with dynamic Huffman codes (BTYPE=10)
+    let hclens = field(&dht.hclens, index, "hclens")?;
+    check_code_lengths(
+        &hclens.iter().map(|x| x.v).collect::<Vec<u8>>(),
+        7,
+        index,
+        "hclens",
+    )?;
+    for hclen in hclens {
+        writer.write_bits(u32::from(hclen.v), 3);
+    }
+    let hclens_codes = build_huffman_codes(&CODE_LENGTH_ALPHABET, hclens);
+    let hclens_table = code_table(&hclens_codes);
+    // Keep the original run-length encoding only if it still stores `hlits`, e.g. not after
+    // `hlits` was edited
+    let rle = match &dht.hlits_rle {
+        Some(rle) if run_length_decode(rle).as_deref() == Some(hlits) => rle
+            .iter()
+            .map(|code| {
+                let repeat = code.repeat.as_ref().map_or(0, |x| u32::from(x.v));
+                let repeat_len = match code.symbol.v {
+                    16 => 2,
+                    17 => 3,
+                    18 => 7,
+                    _ => 0,
+                };
+                (code.symbol.v, repeat, repeat_len)
+            })
+            .collect(),
+        _ => run_length_encode(hlits, |symbol| {
+            matches!(hclens_table.get(symbol as usize), Some(Some(_)))
+        }),
+    };
+    for (symbol, repeat, repeat_len) in rle {
+        write_symbol(writer, &hclens_table, symbol, index)?;
+        writer.write_bits(repeat, repeat_len);
+    }
+    Ok(())
+}
+
+fn encode_tokens(
+    writer: &mut BitWriter,
+    tokens: &Option<Vec<Value<Token>>>,
+    hlits_codes: &[HuffmanCode<u16>],
+    hdists_codes: &[HuffmanCode<u8>],
+    index: usize,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 3.2.5. Compressed blocks (length and distance codes)
+    let hlits_table = code_table(hlits_codes);
+    let hdists_table = code_table(hdists_codes);
+    for token in field(tokens, index, "tokens")? {
+        match &token.v {
+            Token::Literal(literal) => {
+                write_symbol(writer, &hlits_table, u16::from(literal.v), index)?
+            }
+            Token::Eob(_) => write_symbol(writer, &hlits_table, 256, index)?,
+            Token::Window(window) => {
+                let length = window.length.v;
+                if !(257..=285).contains(&length) {
+                    return Err(encode_error(index, &format!("Length code {}", length)));
+                }
+                write_symbol(writer, &hlits_table, length, index)?;
+                let (length_extras, _) = length_code(length, settings);
+                writer.write_bits(u32::from(window.length_extra.v), length_extras);
+                let distance = window.distance.v;
+                write_symbol(writer, &hdists_table, distance, index)?;
+                let distance_extras = DISTANCE_EXTRAS
+                    .get(distance as usize)
+                    .ok_or_else(|| encode_error(index, &format!("Distance code {}", distance)))?;
+                writer.write_bits(u32::from(window.distance_extra.v), *distance_extras);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_dynamic(
+    writer: &mut BitWriter,
+    dht: &DynamicHuffmanTable,
+    tokens: &Option<Vec<Value<Token>>>,
+    index: usize,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 3.2.7. Compression with dynamic Huffman codes (BTYPE=10)
+    write_field(writer, &dht.hlit, 5, index, "hlit")?;
+    write_field(writer, &dht.hdist, 5, index, "hdist")?;
+    write_field(writer, &dht.hclen, 4, index, "hclen")?;
+    let hlits = field(&dht.hlits, index, "hlits")?;
+    let hlits_count = field(&dht.hlit, index, "hlit")?.v as usize + 257;
+    let hdists_count = field(&dht.hdist, index, "hdist")?.v as usize + 1;
+    if hlits.len() != hlits_count + hdists_count {
+        return Err(encode_error(index, "Number of code lengths"));
+    }
+    let lens = hlits.iter().map(|x| x.v).collect::<Vec<u8>>();
+    check_code_lengths(&lens, 15, index, "hlits")?;
+    encode_code_lengths(writer, dht, &lens, index)?;
+    let hlits_codes = build_huffman_codes(&LITERAL_LENGTH_ALPHABET, &hlits[..hlits_count]);
+    let hdists_codes = build_huffman_codes(&DISTANCE_ALPHABET, &hlits[hlits_count..]);
+    encode_tokens(writer, tokens, &hlits_codes, &hdists_codes, index, settings)
+}
+
+pub(crate) fn encode_block(
+    writer: &mut BitWriter,
+    block: &DeflateBlock,
+    index: usize,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 3.2.3. Details of block format
+    write_field(writer, &block.header.bfinal, 1, index, "bfinal")?;
+    write_field(writer, &block.header.btype, 2, index, "btype")?;
+    match &block.ext {
+        Some(DeflateBlockExt::Stored(stored)) => encode_stored(writer, stored, index),
+        Some(DeflateBlockExt::Fixed(fixed)) => {
+            let (hlits_codes, hdists_codes) = fixed_huffman_codes(0);
+            encode_tokens(
+                writer,
+                &fixed.tokens,
+                &hlits_codes,
+                &hdists_codes,
+                index,
+                settings,
+            )
+        }
+        Some(DeflateBlockExt::Dynamic(dynamic)) => {
+            let dht = field(&dynamic.dht, index, "dht")?;
+            encode_dynamic(writer, dht, &dynamic.tokens, index, settings)
+        }
+        // A block with an invalid BTYPE has nothing after the header
+        None => Ok(()),
+    }
+}
+
+/// Encodes `deflate` starting at a byte boundary. The blocks must have been parsed with
+/// `Settings::data`, since the encoder needs the tokens and the stored data.
+pub fn encode_deflate(deflate: &DeflateStream, settings: &Settings) -> Result<Vec<u8>, Error> {
+    let mut writer = BitWriter::new();
+    for (index, block) in deflate.blocks.iter().enumerate() {
+        encode_block(&mut writer, block, index, settings)?;
+    }
+    writer.align(&deflate.padding);
+    Ok(writer.bytes)
+}
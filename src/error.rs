@@ -1,28 +1,143 @@
-#[derive(Debug, Serialize)]
-#[serde(untagged)]
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+#[derive(Debug)]
 pub enum Error {
-    Io(String),
-    Utf8(String),
+    Io(::std::io::Error),
     Parse(ParseError),
-    Serde(String),
-    Hex(String),
+    Serde(serde_json::Error),
+    Hex(hex::FromHexError),
+    Encode(String),
+}
+
+/// What went wrong, so that callers do not have to match on the messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The data ends prematurely.
+    Eof,
+    /// A byte-aligned field does not start at a byte boundary.
+    Unaligned,
+    /// A Huffman code, a symbol or an enumerated field value is not valid.
+    InvalidCode,
+    /// The code lengths do not describe a valid Huffman table.
+    InvalidHuffmanTable,
+    /// A distance points before the start of the window.
+    DistanceTooFar,
+    /// A header field has an invalid or unexpected value.
+    BadHeader,
+    /// A stored checksum does not match the computed one.
+    ChecksumMismatch,
+    /// A stored size or count does not match the computed one.
+    SizeMismatch,
+    /// The NLEN of a stored block is not the one's complement of its LEN.
+    StoredLenMismatch,
+    /// Two structures that must agree do not, or a reference does not resolve.
+    Inconsistent,
+    /// A valid feature that the parser does not handle.
+    Unsupported,
+    /// There is data after the end of the stream.
+    Garbage,
+    /// A parse error that fits none of the other kinds.
+    Other,
+    /// Reading the input or writing the output failed.
+    Io,
+    /// A JSON model or description cannot be read or written.
+    Json,
+    /// A hex-encoded field of the model is not valid hex.
+    Hex,
+    /// The model cannot be encoded back into a deflate stream.
+    Encode,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ParseError {
+    pub kind: ErrorKind,
     pub pos: usize,
     pub msg: String,
+    pub context: Option<Box<ErrorContext>>,
 }
 
-impl From<::std::io::Error> for Error {
-    fn from(error: ::std::io::Error) -> Self {
-        Error::Io(error.to_string())
+/// Where in the deflate stream a parse error happened.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ErrorContext {
+    /// Index of the block in its deflate stream.
+    pub block: Option<usize>,
+    pub block_start: Option<usize>,
+    pub btype: Option<u8>,
+    /// Path of the field in the block, e.g. `dht.hclens[3]` or `tokens[12].distance_extra`.
+    pub field: Option<String>,
+    /// The literal/length symbol of the token being decoded.
+    pub symbol: Option<u16>,
+    pub plain_pos: Option<usize>,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(_) => ErrorKind::Io,
+            Error::Parse(error) => error.kind,
+            Error::Serde(_) => ErrorKind::Json,
+            Error::Hex(_) => ErrorKind::Hex,
+            Error::Encode(_) => ErrorKind::Encode,
+        }
+    }
+
+    /// The context of a parse error, created empty on first use.
+    pub(crate) fn context(&mut self) -> Option<&mut ErrorContext> {
+        match self {
+            Error::Parse(error) => Some(error.context.get_or_insert_with(Box::default)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Parse(error) => error.fmt(f),
+            Error::Serde(error) => write!(f, "JSON error: {}", error),
+            Error::Hex(error) => write!(f, "Hex error: {}", error),
+            Error::Encode(msg) => write!(f, "Encode error: {}", msg),
+        }
     }
 }
 
-impl From<::std::str::Utf8Error> for Error {
-    fn from(error: ::std::str::Utf8Error) -> Self {
-        Error::Utf8(error.to_string())
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at bit {}", self.msg, self.pos)
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Parse(_) => None,
+            Error::Serde(error) => Some(error),
+            Error::Hex(error) => Some(error),
+            Error::Encode(_) => None,
+        }
+    }
+}
+
+impl ::std::error::Error for ParseError {}
+
+// The JSON output keeps the shape it had when the wrapped errors were stored as strings
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Error::Parse(error) => error.serialize(serializer),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+impl From<::std::io::Error> for Error {
+    fn from(error: ::std::io::Error) -> Self {
+        Error::Io(error)
     }
 }
 
@@ -34,12 +149,12 @@ impl From<ParseError> for Error {
 
 impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
-        Error::Serde(error.to_string())
+        Error::Serde(error)
     }
 }
 
 impl From<hex::FromHexError> for Error {
     fn from(error: hex::FromHexError) -> Self {
-        Error::Hex(error.to_string())
+        Error::Hex(error)
     }
 }
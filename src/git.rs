@@ -0,0 +1,258 @@
+use crate::data::{GitFile, GitObject, Value, ZlibStream};
+use crate::error::{Error, ErrorKind, ParseError};
+use crate::{parse_zlib, DataStream, Settings, Window};
+
+// gitformat-pack: 4-byte signature: The signature is: {'P', 'A', 'C', 'K'}
+const PACK_SIGNATURE: u32 = 0x5041434b;
+// gitformat-pack: Object types
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+const HASH_LEN: usize = 20;
+
+fn type_name(object_type: u8) -> Option<&'static str> {
+    match object_type {
+        1 => Some("commit"),
+        2 => Some("tree"),
+        3 => Some("blob"),
+        4 => Some("tag"),
+        OBJ_OFS_DELTA => Some("ofs-delta"),
+        OBJ_REF_DELTA => Some("ref-delta"),
+        _ => None,
+    }
+}
+
+fn new_object(offset: usize) -> GitObject {
+    GitObject {
+        offset,
+        object_type: None,
+        type_name: None,
+        size: None,
+        base_offset: None,
+        base_offset_computed: None,
+        base_name: None,
+        header: None,
+        zlib: None,
+        end: None,
+        size_computed: None,
+        warnings: Vec::new(),
+    }
+}
+
+fn pop_byte(data: &mut DataStream) -> Result<u8, Error> {
+    let mut byte: Option<Value<u8>> = None;
+    Ok(data.pop_le(&mut byte)?.v)
+}
+
+fn parse_object_header(object: &mut GitObject, data: &mut DataStream) -> Result<u8, Error> {
+    // n-byte type and length (3-bit type, (n-1)*7+4-bit length)
+    let start = data.pos;
+    let mut byte = pop_byte(data)?;
+    let object_type = (byte >> 4) & 7;
+    object.object_type = Some(Value {
+        v: object_type,
+        start: start + 4,
+        end: start + 7,
+    });
+    object.type_name = type_name(object_type).map(String::from);
+    if object.type_name.is_none() {
+        object.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: start,
+            msg: format!("Object type={}", object_type),
+            context: None,
+        });
+    }
+    let mut size = u64::from(byte & 0xf);
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = pop_byte(data)?;
+        size |= u64::from(byte & 0x7f)
+            .checked_shl(shift)
+            .filter(|x| x >> shift == u64::from(byte & 0x7f))
+            .ok_or_else(|| data.parse_error(ErrorKind::BadHeader, "Object size is too large"))?;
+        shift += 7;
+    }
+    object.size = Some(Value {
+        v: size,
+        start,
+        end: data.pos,
+    });
+    Ok(object_type)
+}
+
+fn parse_base_offset(
+    object: &mut GitObject,
+    data: &mut DataStream,
+    offsets: &[usize],
+) -> Result<(), Error> {
+    // offset encoding: n bytes with MSB set in all but the last one. The offset is then the
+    // number constructed by concatenating the lower 7 bit of each byte, and for n >= 2 adding
+    // 2^7 + 2^14 + ... + 2^(7*(n-1)) to the result.
+    let start = data.pos;
+    let mut byte = pop_byte(data)?;
+    let mut offset = u64::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        byte = pop_byte(data)?;
+        offset = offset
+            .checked_add(1)
+            .and_then(|x| x.checked_mul(0x80))
+            .ok_or_else(|| data.parse_error(ErrorKind::BadHeader, "Base offset is too large"))?
+            | u64::from(byte & 0x7f);
+    }
+    object.base_offset = Some(Value {
+        v: offset,
+        start,
+        end: data.pos,
+    });
+    let base = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| object.offset.checked_sub(offset));
+    object.base_offset_computed = base;
+    if !base.is_some_and(|base| offsets.contains(&base)) {
+        object.warnings.push(ParseError {
+            kind: ErrorKind::Inconsistent,
+            pos: start,
+            msg: String::from("Base offset does not point to an object"),
+            context: None,
+        });
+    }
+    Ok(())
+}
+
+fn parse_packed_object(
+    out: &mut Vec<GitObject>,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let offsets = out
+        .iter()
+        .map(|object| object.offset)
+        .collect::<Vec<usize>>();
+    out.push(new_object(data.byte_index()?));
+    let object = match out.last_mut() {
+        Some(x) => x,
+        None => unreachable!(),
+    };
+    match parse_object_header(object, data)? {
+        OBJ_OFS_DELTA => parse_base_offset(object, data, &offsets)?,
+        OBJ_REF_DELTA => {
+            data.pop_hex(&mut object.base_name, HASH_LEN)?;
+        }
+        _ => {}
+    }
+    // The end of the compressed data is only known after decompressing it
+    window.reset();
+    window.plain_pos = 0;
+    let zlib = object.zlib.insert(ZlibStream::default());
+    parse_zlib(zlib, data, window, settings)?;
+    object.end = Some(data.pos);
+    object.size_computed = Some(window.plain_pos);
+    if let Some(size) = &object.size {
+        if size.v != window.plain_pos as u64 {
+            object.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: size.start,
+                msg: format!("Object size (computed={})", window.plain_pos),
+                context: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse_pack(
+    git: &mut GitFile,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // gitformat-pack: pack-*.pack files have the following format
+    data.pop_be(&mut git.signature)?;
+    let version = data.pop_be(&mut git.version)?;
+    if version.v != 2 && version.v != 3 {
+        git.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: version.start,
+            msg: format!("Version={}", version.v),
+            context: None,
+        });
+    }
+    let count = data.pop_be(&mut git.count)?.clone();
+    while git.objects.len() < count.v as usize && data.end - data.pos > HASH_LEN * 8 {
+        parse_packed_object(&mut git.objects, data, window, settings)?;
+    }
+    if git.objects.len() != count.v as usize {
+        git.warnings.push(ParseError {
+            kind: ErrorKind::SizeMismatch,
+            pos: count.start,
+            msg: format!("Object count (parsed={})", git.objects.len()),
+            context: None,
+        });
+    }
+    // The trailer records a pack checksum of all of the above
+    data.pop_hex(&mut git.checksum, HASH_LEN)?;
+    Ok(())
+}
+
+fn parse_loose_object(
+    git: &mut GitFile,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    git.objects.push(new_object(data.byte_index()?));
+    let object = match git.objects.last_mut() {
+        Some(x) => x,
+        None => unreachable!(),
+    };
+    window.reset();
+    window.plain_pos = 0;
+    window.capture = Some(Vec::new());
+    let zlib = object.zlib.insert(ZlibStream::default());
+    let result = parse_zlib(zlib, data, window, settings);
+    let plain = window.capture.take().unwrap_or_default();
+    if result.is_ok() {
+        object.end = Some(data.pos);
+    }
+    // The object header is "<type> <size>\0", followed by the object contents
+    match plain.iter().position(|byte| *byte == 0) {
+        Some(nul) => {
+            let header = String::from_utf8_lossy(&plain[..nul]).into_owned();
+            let size_computed = plain.len() - nul - 1;
+            object.size_computed = Some(size_computed);
+            let size = header
+                .split_once(' ')
+                .and_then(|(_, size)| size.parse::<usize>().ok());
+            if size != Some(size_computed) {
+                object.warnings.push(ParseError {
+                    kind: ErrorKind::SizeMismatch,
+                    pos: object.offset * 8,
+                    msg: format!("Object size (computed={})", size_computed),
+                    context: None,
+                });
+            }
+            object.type_name = header.split(' ').next().map(String::from);
+            object.header = Some(header);
+        }
+        None => object.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: object.offset * 8,
+            msg: String::from("Object header is not terminated"),
+            context: None,
+        }),
+    }
+    result
+}
+
+pub(crate) fn parse_git(
+    git: &mut GitFile,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    match data.peek_be::<u32>() {
+        Ok(signature) if signature.v == PACK_SIGNATURE => parse_pack(git, data, window, settings),
+        _ => parse_loose_object(git, data, window, settings),
+    }
+}
@@ -13,60 +13,179 @@ use std::slice;
 
 use num::PrimInt;
 
+use checksum::{crc32, Adler32, Crc32};
 use data::{
-    CompressedStream, DeflateBlock, DeflateBlockDynamic, DeflateBlockExt, DeflateBlockFixed,
-    DeflateBlockHeader, DeflateBlockStored, DeflateStream, DynamicHuffmanTable, EobToken,
-    GzipStream, HuffmanCode, HuffmanTree, LiteralToken, Token, Value, WindowToken, ZlibStream,
+    BgzfBlock, BgzfOffset, CodeLengthCode, CompressedStream, DeflateBlock, DeflateBlockDynamic,
+    DeflateBlockExt, DeflateBlockFixed, DeflateBlockHeader, DeflateBlockStored, DeflateStream,
+    DetectedStream, DynamicHuffmanTable, EobToken, GzipExtra, GzipExtraSubfield, GzipFile,
+    GzipStream, HuffmanCode, HuffmanTree, Latin1String, LiteralToken, Token, Value, WindowToken,
+    ZlibStream,
 };
-use error::{Error, ParseError};
+use error::{Error, ErrorContext, ErrorKind, ParseError};
+use visitor::{ContainerHeader, Model, ParseVisitor};
 
+pub mod assembler;
+mod checksum;
 pub mod data;
+pub mod encoder;
 pub mod error;
+mod git;
+mod pdf;
+mod png;
+mod scan;
+pub mod visitor;
+mod websocket;
+mod zip;
 
-impl DataStream {
-    fn new(path: &Path, pos: usize) -> Result<DataStream, Error> {
+impl<'s> DataStream<'s> {
+    fn new(path: &Path, pos: usize) -> Result<DataStream<'s>, Error> {
         let mut f = File::open(path)?;
         let len: usize = f.seek(SeekFrom::End(0))? as usize;
         f.seek(SeekFrom::Start(0))?;
-        let mut bytes = Vec::new();
-        bytes.resize(len, 0);
+        let mut bytes = vec![0; len];
         f.read_exact(&mut bytes)?;
-        Ok(DataStream {
+        Ok(DataStream::from_bytes(bytes, pos))
+    }
+
+    fn from_bytes(bytes: Vec<u8>, pos: usize) -> DataStream<'s> {
+        let end = bytes.len() * 8;
+        DataStream {
             bytes,
+            base: 0,
             pos,
-            end: len * 8,
-        })
+            end,
+            source: None,
+            visitor: Box::new(Model),
+            keep_blocks: true,
+            dropped_blocks: 0,
+            retain: None,
+            strict: false,
+        }
     }
 
-    fn require(&self, n: usize) -> Result<(), Error> {
-        if self.pos + n <= self.end {
-            Ok(())
-        } else {
-            Err(self.parse_error("EOF"))
+    fn from_reader<R: Read + 's, V: ParseVisitor + 's>(
+        reader: R,
+        pos: usize,
+        visitor: V,
+    ) -> DataStream<'s> {
+        DataStream {
+            source: Some(Box::new(reader)),
+            keep_blocks: visitor.keep_blocks(),
+            visitor: Box::new(visitor),
+            ..DataStream::from_bytes(Vec::new(), pos)
+        }
+    }
+
+    /// Reads the next chunk of a streaming source, returns whether there was one.
+    fn fill(&mut self) -> Result<bool, Error> {
+        let source = match &mut self.source {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+        let len = self.bytes.len();
+        self.bytes.resize(len + CHUNK_LEN, 0);
+        let n = loop {
+            match source.read(&mut self.bytes[len..]) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                result => break result,
+            }
+        };
+        let n = n.inspect_err(|_| self.bytes.truncate(len))?;
+        self.bytes.truncate(len + n);
+        self.end += n * 8;
+        if n == 0 {
+            self.source = None;
+        }
+        Ok(n != 0)
+    }
+
+    /// Buffers at least `n` more bits, or as many as there are.
+    fn fill_to(&mut self, n: usize) -> Result<(), Error> {
+        while self.pos.saturating_add(n) > self.end && self.fill()? {}
+        Ok(())
+    }
+
+    fn at_end(&mut self) -> Result<bool, Error> {
+        self.fill_to(1)?;
+        Ok(self.pos >= self.end)
+    }
+
+    /// Buffers the rest of the input for the parsers that need random access to it. The consumed
+    /// bytes must not be discarded from now on, so the blocks are kept in the model as well as
+    /// reported to the visitor.
+    fn read_to_end(&mut self) -> Result<(), Error> {
+        while self.fill()? {}
+        self.keep_blocks = true;
+        Ok(())
+    }
+
+    /// Drops the blocks that the visitor does not keep, and discards the bytes that were consumed.
+    fn drop_blocks(&mut self, blocks: &mut Vec<DeflateBlock>) {
+        if self.keep_blocks {
+            return;
+        }
+        self.dropped_blocks += blocks.len();
+        blocks.clear();
+        let keep = (self.pos / 8).min(self.retain.unwrap_or(usize::MAX));
+        if keep > self.base {
+            self.bytes.drain(..keep - self.base);
+            self.base = keep;
         }
     }
 
+    fn require(&mut self, n: usize) -> Result<(), Error> {
+        self.fill_to(n)?;
+        match self.pos.checked_add(n) {
+            Some(end) if end <= self.end => Ok(()),
+            _ => Err(self.parse_error(ErrorKind::Eof, "EOF")),
+        }
+    }
+
+    /// The number of bits in `n` bytes, which are not there if it overflows.
+    fn bits(&self, n: usize) -> Result<usize, Error> {
+        n.checked_mul(8)
+            .ok_or_else(|| self.parse_error(ErrorKind::Eof, "EOF"))
+    }
+
     fn byte_index(&self) -> Result<usize, Error> {
-        if self.pos % 8 == 0 {
+        if self.pos.is_multiple_of(8) {
             Ok(self.pos / 8)
         } else {
-            Err(self.parse_error("Unaligned"))
+            Err(self.parse_error(ErrorKind::Unaligned, "Unaligned"))
         }
     }
 
-    fn peek_le<T: PrimInt>(&self) -> Result<Value<T>, Error> {
+    fn peek_le<T: PrimInt>(&mut self) -> Result<Value<T>, Error> {
         let bytes = size_of::<T>();
         self.require(bytes * 8)?;
         let index = self.byte_index()?;
         let mut v = T::zero();
         for i in 0..bytes {
-            let b = T::from(self.bytes[index + i]).ok_or_else(|| self.parse_error("Conversion"))?;
+            let b = T::from(self.bytes[index - self.base + i])
+                .ok_or_else(|| self.parse_error(ErrorKind::Other, "Conversion"))?;
             v = v | (b << (i * 8));
         }
         Ok(Value {
             v,
             start: self.pos,
-            end: self.pos + 8,
+            end: self.pos + bytes * 8,
+        })
+    }
+
+    fn peek_be<T: PrimInt>(&mut self) -> Result<Value<T>, Error> {
+        let bytes = size_of::<T>();
+        self.require(bytes * 8)?;
+        let index = self.byte_index()?;
+        let mut v = T::zero();
+        for i in 0..bytes {
+            let b = T::from(self.bytes[index - self.base + i])
+                .ok_or_else(|| self.parse_error(ErrorKind::Other, "Conversion"))?;
+            v = v | (b << ((bytes - 1 - i) * 8));
+        }
+        Ok(Value {
+            v,
+            start: self.pos,
+            end: self.pos + bytes * 8,
         })
     }
 
@@ -82,16 +201,30 @@ impl DataStream {
         })
     }
 
+    fn pop_be<'a, T: PrimInt>(
+        &mut self,
+        out: &'a mut Option<Value<T>>,
+    ) -> Result<&'a Value<T>, Error> {
+        *out = Some(self.peek_be::<T>()?);
+        self.pos += size_of::<T>() * 8;
+        Ok(match out {
+            Some(x) => x,
+            None => unreachable!(),
+        })
+    }
+
     fn drop(&mut self, n: usize) -> Result<(), Error> {
         self.require(n)?;
         self.pos += n;
         Ok(())
     }
 
-    fn parse_error(&self, msg: &str) -> Error {
+    fn parse_error(&self, kind: ErrorKind, msg: &str) -> Error {
         Error::from(ParseError {
+            kind,
             pos: self.pos,
             msg: String::from(msg),
+            context: None,
         })
     }
 
@@ -100,7 +233,8 @@ impl DataStream {
         let mut v = T::zero();
         for i in 0..n {
             let pos = self.pos + i;
-            let b = T::from(self.bytes[pos / 8]).ok_or_else(|| self.parse_error("Conversion"))?;
+            let b = T::from(self.bytes[pos / 8 - self.base])
+                .ok_or_else(|| self.parse_error(ErrorKind::Other, "Conversion"))?;
             v = v | (((b >> (pos % 8)) & T::one()) << i);
         }
         Ok(Value {
@@ -124,9 +258,11 @@ impl DataStream {
         Ok(bits)
     }
 
-    fn align(&mut self) -> Result<(), Error> {
+    /// Skips to the byte boundary and records the skipped bits in `out`.
+    fn align(&mut self, out: &mut Option<Value<u8>>) -> Result<(), Error> {
         let n = (8 - (self.pos & 7)) & 7;
-        self.drop(n)
+        self.pop_bits(out, n)?;
+        Ok(())
     }
 
     fn pop_bytes(
@@ -136,9 +272,9 @@ impl DataStream {
         settings: &Settings,
     ) -> Result<&[u8], Error> {
         let index = self.byte_index()?;
-        let bits = n * 8;
+        let bits = self.bits(n)?;
         self.require(bits)?;
-        let data = &self.bytes[index..index + n];
+        let data = &self.bytes[index - self.base..index - self.base + n];
         if settings.data {
             *out = Some(Value {
                 v: hex::encode(data),
@@ -150,17 +286,35 @@ impl DataStream {
         Ok(data)
     }
 
-    fn pop_str(&mut self, out: &mut Option<Value<String>>) -> Result<(), Error> {
+    fn pop_hex(&mut self, out: &mut Option<Value<String>>, n: usize) -> Result<&[u8], Error> {
+        let index = self.byte_index()?;
+        let bits = self.bits(n)?;
+        self.require(bits)?;
+        *out = Some(Value {
+            v: hex::encode(&self.bytes[index - self.base..index - self.base + n]),
+            start: self.pos,
+            end: self.pos + bits,
+        });
+        self.pos += bits;
+        Ok(&self.bytes[index - self.base..index - self.base + n])
+    }
+
+    fn pop_str(&mut self, out: &mut Option<Value<Latin1String>>) -> Result<(), Error> {
         let start = self.byte_index()?;
         let mut pos = start;
         loop {
             self.require(8)?;
-            let byte = self.bytes[pos];
+            let byte = self.bytes[pos - self.base];
             pos += 1;
             self.pos += 8;
             if byte == 0 {
+                let bytes = &self.bytes[start - self.base..pos - 1 - self.base];
                 *out = Some(Value {
-                    v: std::str::from_utf8(&self.bytes[start..pos - 1])?.to_owned(),
+                    v: Latin1String {
+                        // ISO 8859-1 maps each byte to the Unicode code point with the same value
+                        text: bytes.iter().map(|byte| char::from(*byte)).collect(),
+                        hex: hex::encode(bytes),
+                    },
                     start: start * 8,
                     end: pos * 8,
                 });
@@ -170,10 +324,68 @@ impl DataStream {
     }
 }
 
-struct DataStream {
+// Reads from a streaming source are done in chunks of this size
+const CHUNK_LEN: usize = 0x10000;
+
+struct DataStream<'a> {
     bytes: Vec<u8>,
+    // The index of the first byte in `bytes`. Streaming parses discard the bytes that are no
+    // longer needed, all the other parses keep it at 0
+    base: usize,
     pos: usize,
     end: usize,
+    // The rest of the input of a streaming parse, `None` once it is all in `bytes`
+    source: Option<Box<dyn Read + 'a>>,
+    visitor: Box<dyn ParseVisitor + 'a>,
+    keep_blocks: bool,
+    // The number of blocks of the current deflate stream that were dropped from the model
+    dropped_blocks: usize,
+    // The index of the first byte that must not be discarded, e.g. the start of a BGZF member
+    retain: Option<usize>,
+    // Whether the inconsistencies that are normally warnings are errors, e.g. while scanning
+    strict: bool,
+}
+
+/// Records the field that was being parsed when `result` failed, unless a nested call already
+/// did.
+fn in_field<T>(result: Result<T, Error>, field: impl FnOnce() -> String) -> Result<T, Error> {
+    result.map_err(|mut err| {
+        if let Some(context) = err.context() {
+            context.field.get_or_insert_with(field);
+        }
+        err
+    })
+}
+
+fn in_symbol<T>(result: Result<T, Error>, symbol: u16) -> Result<T, Error> {
+    result.map_err(|mut err| {
+        if let Some(context) = err.context() {
+            context.symbol = Some(symbol);
+        }
+        err
+    })
+}
+
+/// Records the block that was being parsed when `result` failed.
+fn in_block<T>(
+    result: Result<T, Error>,
+    index: usize,
+    start: usize,
+    btype: Option<u8>,
+    window: &Window,
+) -> Result<T, Error> {
+    result.map_err(|mut err| {
+        if let Some(context) = err.context() {
+            *context = ErrorContext {
+                block: Some(index),
+                block_start: Some(start),
+                btype,
+                plain_pos: Some(window.plain_pos),
+                ..std::mem::take(context)
+            };
+        }
+        err
+    })
 }
 
 fn parse_hclens<'a>(
@@ -187,9 +399,9 @@ fn parse_hclens<'a>(
         Some(x) => x,
         None => unreachable!(),
     };
-    for _ in 0..n {
+    for i in 0..n {
         let mut bits: Option<Value<u8>> = None;
-        data.pop_bits(&mut bits, 3)?;
+        in_field(data.pop_bits(&mut bits, 3), || format!("dht.hclens[{}]", i))?;
         hclens.push(bits.expect("bits"));
     }
     Ok(hclens)
@@ -218,8 +430,10 @@ fn add_to_huffman_tree<T: Debug>(
             Ok(())
         } else {
             Err(Error::Parse(ParseError {
+                kind: ErrorKind::InvalidHuffmanTable,
                 pos,
                 msg: format!("Not an empty leaf (symbol={:?})", symbol),
+                context: None,
             }))
         }
     } else {
@@ -232,6 +446,7 @@ fn add_to_huffman_tree<T: Debug>(
                 add_to_huffman_tree(&mut children[bit], pos + 1, code, len - 1, symbol)
             }
             _ => Err(Error::Parse(ParseError {
+                kind: ErrorKind::InvalidHuffmanTable,
                 pos,
                 msg: match tree {
                     HuffmanTree::Leaf(Some(old_symbol)) => {
@@ -239,11 +454,35 @@ fn add_to_huffman_tree<T: Debug>(
                     }
                     _ => format!("Conflict (symbol={:?})", symbol),
                 },
+                context: None,
             })),
         }
     }
 }
 
+const LITERAL_LENGTH_ALPHABET: [u16; 286] = {
+    let mut alphabet = [0; 286];
+    let mut i = 0;
+    while i < alphabet.len() {
+        alphabet[i] = i as u16;
+        i += 1;
+    }
+    alphabet
+};
+const DISTANCE_ALPHABET: [u8; 32] = {
+    let mut alphabet = [0; 32];
+    let mut i = 0;
+    while i < alphabet.len() {
+        alphabet[i] = i as u8;
+        i += 1;
+    }
+    alphabet
+};
+// 3.2.7. The code length code lengths are stored in this order
+const CODE_LENGTH_ALPHABET: [u8; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
 fn code_to_bin(out: &mut String, code: u16, len: usize) {
     for i in (0..len).rev() {
         out.push(if (code & (1 << i)) == 0 { '0' } else { '1' });
@@ -265,7 +504,8 @@ fn build_huffman_codes<T: Clone + Ord>(alphabet: &[T], lens: &[Value<u8>]) -> Ve
     let mut code: u16 = 0;
     bl_count[0] = 0;
     for bits in 1..=MAX_BITS {
-        code = (code + bl_count[bits - 1]) << 1;
+        // Oversubscribed code lengths wrap around, the tree rejects the conflicting codes
+        code = code.wrapping_add(bl_count[bits - 1]) << 1;
         next_code[bits] = code;
     }
 
@@ -290,7 +530,7 @@ fn build_huffman_codes<T: Clone + Ord>(alphabet: &[T], lens: &[Value<u8>]) -> Ve
         let len_usize = code.len.v as usize;
         code.code = next_code[len_usize];
         code_to_bin(&mut code.bin, next_code[len_usize], len_usize);
-        next_code[len_usize] += 1;
+        next_code[len_usize] = next_code[len_usize].wrapping_add(1);
     }
     codes
 }
@@ -343,13 +583,14 @@ fn parse_huffman_code<T: Clone>(
         HuffmanTree::Leaf(None) => {
             let mut bin = String::with_capacity(len);
             code_to_bin(&mut bin, code, len);
-            Err(data.parse_error(&format!("Code=0b{}", bin)))
+            Err(data.parse_error(ErrorKind::InvalidCode, &format!("Code=0b{}", bin)))
         }
     }
 }
 
 fn parse_huffman_code_lengths<'a>(
     out: &'a mut Option<Vec<Value<u8>>>,
+    rle: &mut Option<Vec<CodeLengthCode>>,
     data: &mut DataStream,
     n: usize,
     tree: &HuffmanTree<u8>,
@@ -360,19 +601,31 @@ fn parse_huffman_code_lengths<'a>(
         Some(x) => x,
         None => unreachable!(),
     };
+    let rle = rle.insert(Vec::new());
     while lens.len() < n {
+        let index = lens.len();
+        let field = || format!("dht.hlits[{}]", index);
         let start = data.pos;
-        let value = parse_huffman_code(data, tree, start, 0, 0)?;
+        let value = in_field(parse_huffman_code(data, tree, start, 0, 0), field)?;
         match value.v {
             0..=15 => {
                 // 0 - 15: Represent code lengths of 0 - 15
-                lens.push(value)
+                lens.push(value.clone());
+                rle.push(CodeLengthCode {
+                    symbol: value,
+                    repeat: None,
+                });
             }
             16..=18 => {
                 let (what, start, repeat_add, repeat_len) = match value.v {
                     // 16: Copy the previous code length 3 - 6 times
                     16 => {
-                        let last = lens.last().ok_or_else(|| data.parse_error("Repeat"))?;
+                        let last = in_field(
+                            lens.last().ok_or_else(|| {
+                                data.parse_error(ErrorKind::InvalidHuffmanTable, "Repeat")
+                            }),
+                            field,
+                        )?;
                         (last.v, last.start, 3, 2)
                     }
                     // 17: Repeat a code length of 0 for 3 - 10 times
@@ -381,23 +634,31 @@ fn parse_huffman_code_lengths<'a>(
                     18 => (0, value.start, 11, 7),
                     _ => unreachable!(),
                 };
-                let mut option_repeat: Option<Value<usize>> = None;
-                let repeat = data.pop_bits(&mut option_repeat, repeat_len)?;
-                for _ in 0..(repeat_add + repeat.v) {
+                let mut option_repeat: Option<Value<u8>> = None;
+                let repeat = in_field(data.pop_bits(&mut option_repeat, repeat_len), field)?;
+                for _ in 0..(repeat_add + repeat.v as usize) {
                     lens.push(Value {
                         v: what,
                         start,
                         end: repeat.end,
                     });
                 }
+                rle.push(CodeLengthCode {
+                    symbol: value,
+                    repeat: option_repeat,
+                });
+            }
+            _ => {
+                let err = data.parse_error(ErrorKind::InvalidCode, "Code length");
+                return in_field(Err(err), field);
             }
-            _ => return Err(data.parse_error("Code length")),
         }
     }
     if lens.len() == n {
         Ok(lens)
     } else {
-        Err(data.parse_error("Code lengths"))
+        let err = data.parse_error(ErrorKind::InvalidHuffmanTable, "Code lengths");
+        in_field(Err(err), || String::from("dht.hlits"))
     }
 }
 
@@ -414,8 +675,12 @@ fn parse_deflate_block_header(
         Some(x) => x,
         None => unreachable!(),
     };
-    data.pop_bits(&mut header.bfinal, 1)?;
-    data.pop_bits(&mut header.btype, 2)?;
+    in_field(data.pop_bits(&mut header.bfinal, 1), || {
+        String::from("header.bfinal")
+    })?;
+    in_field(data.pop_bits(&mut header.btype, 2), || {
+        String::from("header.btype")
+    })?;
     Ok(())
 }
 
@@ -424,6 +689,12 @@ pub struct Window {
     offset: usize,
     length: usize,
     plain_pos: usize,
+    crc32: Crc32,
+    adler32: Adler32,
+    dictionary_adler32: Option<u32>,
+    capture: Option<Vec<u8>>,
+    // The plain_pos and the distance of each match, for the parsers that analyze them
+    matches: Option<Vec<(usize, usize)>>,
 }
 
 impl Default for Window {
@@ -433,6 +704,11 @@ impl Default for Window {
             offset: 0,
             length: 0,
             plain_pos: 0,
+            crc32: Crc32::default(),
+            adler32: Adler32::default(),
+            dictionary_adler32: None,
+            capture: None,
+            matches: None,
         }
     }
 }
@@ -446,6 +722,11 @@ impl Window {
             self.offset += 1;
         }
         self.plain_pos += 1;
+        self.crc32.update_byte(byte);
+        self.adler32.update_byte(byte);
+        if let Some(capture) = &mut self.capture {
+            capture.push(byte);
+        }
     }
 
     fn append_bytes(&mut self, bytes: &[u8]) {
@@ -454,7 +735,16 @@ impl Window {
         }
     }
 
+    /// Forget the history at the start of an independent deflate stream, keeping `plain_pos`.
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.length = 0;
+    }
+
     fn append_match(&mut self, distance: usize, length: usize) -> Vec<u8> {
+        if let Some(matches) = &mut self.matches {
+            matches.push((self.plain_pos, distance));
+        }
         let mut bytes = vec![0; length];
         let mut pos = (self.data.len() + self.offset + self.length - distance) % self.data.len();
         for byte in &mut bytes {
@@ -468,10 +758,56 @@ impl Window {
     pub fn append_dictionary_from_file(&mut self, file: &mut File) -> Result<(), Error> {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
-        self.append_bytes(&bytes);
-        self.plain_pos -= bytes.len();
+        self.append_dictionary(&bytes);
         Ok(())
     }
+
+    pub fn append_dictionary(&mut self, bytes: &[u8]) {
+        self.append_bytes(bytes);
+        self.plain_pos -= bytes.len();
+        let mut adler32 = Adler32::default();
+        for byte in bytes {
+            adler32.update_byte(*byte);
+        }
+        self.dictionary_adler32 = Some(adler32.value());
+    }
+}
+
+// 3.2.5. Compressed blocks (length and distance codes)
+const LENGTH_EXTRAS: [usize; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const LENGTH_BASES: [u32; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const DISTANCE_EXTRAS: [usize; 32] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13, 14, 14,
+];
+const DISTANCE_BASES: [u32; 32] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577, 32769, 49153,
+];
+
+/// The number of extra bits and the base length of a length code (257 - 285).
+fn length_code(symbol: u16, settings: &Settings) -> (usize, u32) {
+    let index = symbol as usize - 257;
+    // Deflate64 redefines length code 285 as 3 + 16 extra bits
+    if settings.deflate64 && index == 28 {
+        (16, 3)
+    } else {
+        (LENGTH_EXTRAS[index], LENGTH_BASES[index])
+    }
+}
+
+fn distance_codes(settings: &Settings) -> usize {
+    // Deflate64 adds distance codes 30 and 31
+    if settings.deflate64 {
+        32
+    } else {
+        30
+    }
 }
 
 fn parse_tokens(
@@ -487,9 +823,14 @@ fn parse_tokens(
         *out = Some(Vec::new());
     }
     let mut is_eob = false;
+    let mut index = 0;
     while !is_eob {
+        let field = |name: &str| format!("tokens[{}]{}", index, name);
         let start = data.pos;
-        let literal = parse_huffman_code(data, hlits_tree, start, 0, 0)?;
+        let literal = in_field(parse_huffman_code(data, hlits_tree, start, 0, 0), || {
+            field("")
+        })?;
+        let symbol = literal.v;
         let token_plain_pos = window.plain_pos;
         let v = match literal.v {
             0..=255 => {
@@ -509,38 +850,45 @@ fn parse_tokens(
                 })
             }
             257..=285 => {
-                let literal_extras = [
-                    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5,
-                    5, 5, 0,
-                ];
-                let literal_bases: [u16; 29] = [
-                    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67,
-                    83, 99, 115, 131, 163, 195, 227, 258,
-                ];
-                let distance_extras = [
-                    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11,
-                    11, 12, 12, 13, 13,
-                ];
-                let distance_bases = [
-                    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769,
-                    1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
-                ];
-                let mut option_literal_extra: Option<Value<u8>> = None;
-                let literal_index = literal.v as usize - 257;
-                let literal_extra =
-                    data.pop_bits(&mut option_literal_extra, literal_extras[literal_index])?;
-                let length_value = literal_bases[literal_index] + u16::from(literal_extra.v);
+                let (length_extras, length_base) = length_code(literal.v, settings);
+                let mut option_literal_extra: Option<Value<u16>> = None;
+                let literal_extra = in_symbol(
+                    in_field(
+                        data.pop_bits(&mut option_literal_extra, length_extras),
+                        || field(".length_extra"),
+                    ),
+                    symbol,
+                )?;
+                let length_value = length_base + u32::from(literal_extra.v);
                 let distance_start = data.pos;
-                let distance = parse_huffman_code(data, hdists_tree, distance_start, 0, 0)?;
+                let distance = in_symbol(
+                    in_field(
+                        parse_huffman_code(data, hdists_tree, distance_start, 0, 0),
+                        || field(".distance"),
+                    ),
+                    symbol,
+                )?;
                 let mut option_distance_extra: Option<Value<u16>> = None;
-                if distance.v as usize >= distance_extras.len() {
-                    return Err(data.parse_error("Distance extra bits"));
+                if distance.v as usize >= distance_codes(settings) {
+                    let err = data.parse_error(ErrorKind::InvalidCode, "Distance extra bits");
+                    return in_symbol(in_field(Err(err), || field(".distance")), symbol);
                 }
-                let distance_extra = data.pop_bits(
-                    &mut option_distance_extra,
-                    distance_extras[distance.v as usize],
+                let distance_extra = in_symbol(
+                    in_field(
+                        data.pop_bits(
+                            &mut option_distance_extra,
+                            DISTANCE_EXTRAS[distance.v as usize],
+                        ),
+                        || field(".distance_extra"),
+                    ),
+                    symbol,
                 )?;
-                let distance_value = distance_bases[distance.v as usize] + distance_extra.v;
+                let distance_value =
+                    DISTANCE_BASES[distance.v as usize] + u32::from(distance_extra.v);
+                if distance_value as usize > window.length {
+                    let err = data.parse_error(ErrorKind::DistanceTooFar, "Distance too far");
+                    return in_symbol(in_field(Err(err), || field(".distance_value")), symbol);
+                }
                 let data = window.append_match(distance_value as usize, length_value as usize);
                 Token::Window(WindowToken {
                     plain_pos: token_plain_pos,
@@ -553,16 +901,21 @@ fn parse_tokens(
                     hex: hex::encode(&data),
                 })
             }
-            _ => return Err(data.parse_error("Literal")),
+            _ => {
+                let err = data.parse_error(ErrorKind::InvalidCode, "Literal");
+                return in_symbol(in_field(Err(err), || field("")), symbol);
+            }
         };
-        match out {
-            Some(x) => x.push(Value {
-                v,
-                start,
-                end: data.pos,
-            }),
-            None => {}
+        let token = Value {
+            v,
+            start,
+            end: data.pos,
         };
+        data.visitor.on_token(&token)?;
+        if let Some(x) = out {
+            x.push(token)
+        };
+        index += 1;
     }
     Ok(())
 }
@@ -574,15 +927,56 @@ fn parse_deflate_block_stored(
     settings: &Settings,
 ) -> Result<(), Error> {
     // 3.2.4. Non-compressed blocks (BTYPE=00)
-    data.align()?;
-    let len = data.pop_le(&mut out.len)?;
+    in_field(data.align(&mut out.padding), || String::from("padding"))?;
+    let len = in_field(data.pop_le(&mut out.len), || String::from("len"))?;
     let len_usize = len.v as usize;
-    data.pop_le(&mut out.nlen)?;
-    let data = data.pop_bytes(&mut out.data, len_usize, settings)?;
+    let nlen = in_field(data.pop_le(&mut out.nlen), || String::from("nlen"))?;
+    // NLEN is the one's complement of LEN
+    if nlen.v != !len.v {
+        let msg = format!("NLEN (expected=0x{:04x})", !len.v);
+        if data.strict {
+            return Err(Error::from(ParseError {
+                kind: ErrorKind::StoredLenMismatch,
+                pos: nlen.start,
+                msg,
+                context: None,
+            }));
+        }
+        out.warnings.push(ParseError {
+            kind: ErrorKind::StoredLenMismatch,
+            pos: nlen.start,
+            msg,
+            context: None,
+        });
+    }
+    let data = in_field(data.pop_bytes(&mut out.data, len_usize, settings), || {
+        String::from("data")
+    })?;
     window.append_bytes(data);
     Ok(())
 }
 
+/// The literal/length and distance codes of the fixed Huffman code, as if their lengths were
+/// stored at `pos`.
+fn fixed_huffman_codes(pos: usize) -> (Vec<HuffmanCode<u16>>, Vec<HuffmanCode<u8>>) {
+    // 3.2.6. Compression with fixed Huffman codes (BTYPE=01)
+    let len = |v| Value {
+        v,
+        start: pos,
+        end: pos,
+    };
+    let hlits = std::iter::repeat_n(len(8), (0u16..=143).len())
+        .chain(std::iter::repeat_n(len(9), (144u16..=255).len()))
+        .chain(std::iter::repeat_n(len(7), (256u16..=279).len()))
+        .chain(std::iter::repeat_n(len(8), (280u16..=287).len()))
+        .collect::<Vec<Value<u8>>>();
+    let hdists = std::iter::repeat_n(len(5), (0u8..=31).len()).collect::<Vec<Value<u8>>>();
+    (
+        build_huffman_codes(&LITERAL_LENGTH_ALPHABET, &hlits),
+        build_huffman_codes(&DISTANCE_ALPHABET, &hdists),
+    )
+}
+
 fn parse_deflate_block_fixed(
     out: &mut DeflateBlockFixed,
     data: &mut DataStream,
@@ -590,39 +984,9 @@ fn parse_deflate_block_fixed(
     settings: &Settings,
 ) -> Result<(), Error> {
     // Compression with fixed Huffman codes (BTYPE=01)
-    let v5 = Value {
-        v: 5,
-        start: data.pos,
-        end: data.pos,
-    };
-    let v7 = Value {
-        v: 7,
-        start: data.pos,
-        end: data.pos,
-    };
-    let v8 = Value {
-        v: 8,
-        start: data.pos,
-        end: data.pos,
-    };
-    let v9 = Value {
-        v: 9,
-        start: data.pos,
-        end: data.pos,
-    };
-    let hlits = std::iter::repeat(v8.clone())
-        .take((0u16..=143).len())
-        .chain(std::iter::repeat(v9).take((144u16..=255).len()))
-        .chain(std::iter::repeat(v7).take((256u16..=279).len()))
-        .chain(std::iter::repeat(v8).take((280u16..=287).len()))
-        .collect::<Vec<Value<u8>>>();
-    let hlits_codes = build_huffman_codes(&(0..=285).collect::<Vec<u16>>(), &hlits);
+    let (hlits_codes, hdists_codes) = fixed_huffman_codes(data.pos);
     let mut option_hlits_tree: Option<HuffmanTree<u16>> = None;
     let hlits_tree = build_huffman_tree(&mut option_hlits_tree, &hlits_codes)?;
-    let hdists = std::iter::repeat(v5)
-        .take((0u8..=31).len())
-        .collect::<Vec<Value<u8>>>();
-    let hdists_codes = build_huffman_codes(&(0..=31).collect::<Vec<u8>>(), &hdists);
     let mut option_hdists_tree: Option<HuffmanTree<u8>> = None;
     let hdists_tree = build_huffman_tree(&mut option_hdists_tree, &hdists_codes)?;
     parse_tokens(
@@ -639,24 +1003,27 @@ fn parse_deflate_block_fixed(
 fn parse_dht(out: &mut DynamicHuffmanTable, data: &mut DataStream) -> Result<(), Error> {
     // 3.2.7. Compression with dynamic Huffman codes (BTYPE=10)
     // 5 Bits: HLIT, # of Literal/Length codes - 257 (257 - 286)
-    let hlit = data.pop_bits(&mut out.hlit, 5)?;
+    let hlit = in_field(data.pop_bits(&mut out.hlit, 5), || String::from("dht.hlit"))?;
     if hlit.v > 29 {
-        return Err(data.parse_error("HLIT > 29"));
+        let err = data.parse_error(ErrorKind::InvalidHuffmanTable, "HLIT > 29");
+        return in_field(Err(err), || String::from("dht.hlit"));
     }
     // 5 Bits: HDIST, # of Distance codes - 1        (1 - 32)
-    let hdist = data.pop_bits(&mut out.hdist, 5)?;
+    let hdist = in_field(data.pop_bits(&mut out.hdist, 5), || {
+        String::from("dht.hdist")
+    })?;
     // 4 Bits: HCLEN, # of Code Length codes - 4     (4 - 19)
-    let hclen = data.pop_bits(&mut out.hclen, 4)?;
+    let hclen = in_field(data.pop_bits(&mut out.hclen, 4), || {
+        String::from("dht.hclen")
+    })?;
     // (HCLEN + 4) x 3 bits: code lengths for the code length alphabet
     let hclens = parse_hclens(&mut out.hclens, data, hclen.v)?;
-    out.hclens_codes = Some(build_huffman_codes(
-        &[
-            16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
-        ],
-        hclens,
-    ));
+    out.hclens_codes = Some(build_huffman_codes(&CODE_LENGTH_ALPHABET, hclens));
     let hclens_tree = match &out.hclens_codes {
-        Some(hclens_codes) => build_huffman_tree(&mut out.hclens_tree, hclens_codes)?,
+        Some(hclens_codes) => in_field(
+            build_huffman_tree(&mut out.hclens_tree, hclens_codes),
+            || String::from("dht.hclens"),
+        )?,
         None => unreachable!(),
     };
     // HLIT + 257 code lengths for the literal/length alphabet
@@ -665,24 +1032,32 @@ fn parse_dht(out: &mut DynamicHuffmanTable, data: &mut DataStream) -> Result<(),
     let hdists_count = (hdist.v as usize) + 1;
     let hlits_hdists = parse_huffman_code_lengths(
         &mut out.hlits,
+        &mut out.hlits_rle,
         data,
         hlits_count + hdists_count,
         hclens_tree,
     )?;
     out.hlits_codes = Some(build_huffman_codes(
-        &(0..=285).collect::<Vec<u16>>(),
+        &LITERAL_LENGTH_ALPHABET,
         &hlits_hdists[..hlits_count],
     ));
     match &out.hlits_codes {
-        Some(hlits_codes) => build_huffman_tree(&mut out.hlits_tree, hlits_codes)?,
+        Some(hlits_codes) => {
+            in_field(build_huffman_tree(&mut out.hlits_tree, hlits_codes), || {
+                String::from("dht.hlits")
+            })?
+        }
         None => unreachable!(),
     };
     out.hdists_codes = Some(build_huffman_codes(
-        &(0..=29).collect::<Vec<u8>>(),
+        &DISTANCE_ALPHABET,
         &hlits_hdists[hlits_count..],
     ));
     match &out.hdists_codes {
-        Some(hdists_codes) => build_huffman_tree(&mut out.hdists_tree, hdists_codes)?,
+        Some(hdists_codes) => in_field(
+            build_huffman_tree(&mut out.hdists_tree, hdists_codes),
+            || String::from("dht.hdists"),
+        )?,
         None => unreachable!(),
     };
     Ok(())
@@ -700,6 +1075,7 @@ fn parse_deflate_block_dynamic(
         None => unreachable!(),
     };
     parse_dht(dht, data)?;
+    data.visitor.on_dht(dht)?;
     let hlits_tree = match &dht.hlits_tree {
         Some(x) => x,
         None => unreachable!(),
@@ -727,8 +1103,16 @@ fn parse_deflate_block(
     window: &mut Window,
     settings: &Settings,
 ) -> Result<bool, Error> {
+    let index = data.dropped_blocks + out.len();
+    let start = data.pos;
     let mut option_header: Option<DeflateBlockHeader> = None;
-    parse_deflate_block_header(&mut option_header, data)?;
+    in_block(
+        parse_deflate_block_header(&mut option_header, data),
+        index,
+        start,
+        None,
+        window,
+    )?;
     out.push(DeflateBlock {
         header: match option_header {
             Some(x) => x,
@@ -743,6 +1127,7 @@ fn parse_deflate_block(
         Some(x) => x,
         None => unreachable!(),
     };
+    data.visitor.on_block_start(index, block)?;
     let bfinal = match &block.header.bfinal {
         Some(x) => x.v == 1,
         _ => unreachable!(),
@@ -751,18 +1136,20 @@ fn parse_deflate_block(
         Some(btype) => btype.v,
         None => unreachable!(),
     };
-    match btype {
+    let result = match btype {
         0 => {
             block.ext = Some(DeflateBlockExt::Stored(DeflateBlockStored {
+                padding: None,
                 len: None,
                 nlen: None,
                 data: None,
+                warnings: Vec::new(),
             }));
             let ext = match block.ext {
                 Some(DeflateBlockExt::Stored(ref mut x)) => x,
                 _ => unreachable!(),
             };
-            parse_deflate_block_stored(ext, data, window, settings)?;
+            parse_deflate_block_stored(ext, data, window, settings)
         }
         1 => {
             block.ext = Some(DeflateBlockExt::Fixed(DeflateBlockFixed { tokens: None }));
@@ -770,7 +1157,7 @@ fn parse_deflate_block(
                 Some(DeflateBlockExt::Fixed(ref mut x)) => x,
                 _ => unreachable!(),
             };
-            parse_deflate_block_fixed(ext, data, window, settings)?;
+            parse_deflate_block_fixed(ext, data, window, settings)
         }
         2 => {
             block.ext = Some(DeflateBlockExt::Dynamic(Box::new(DeflateBlockDynamic {
@@ -781,12 +1168,17 @@ fn parse_deflate_block(
                 Some(DeflateBlockExt::Dynamic(ref mut x)) => x,
                 _ => unreachable!(),
             };
-            parse_deflate_block_dynamic(ext, data, window, settings)?;
+            parse_deflate_block_dynamic(ext, data, window, settings)
         }
-        _ => return Err(data.parse_error(&format!("BTYPE={}", btype))),
-    }
+        _ => {
+            let err = data.parse_error(ErrorKind::BadHeader, &format!("BTYPE={}", btype));
+            in_field(Err(err), || String::from("header.btype"))
+        }
+    };
+    in_block(result, index, start, Some(btype), window)?;
     block.end = Some(data.pos);
     block.plain_end = Some(window.plain_pos);
+    data.visitor.on_block_end(index, block)?;
     Ok(!bfinal)
 }
 
@@ -796,8 +1188,12 @@ fn parse_deflate(
     window: &mut Window,
     settings: &Settings,
 ) -> Result<(), Error> {
-    while parse_deflate_block(&mut deflate.blocks, data, window, settings)? {}
-    data.align()?;
+    data.dropped_blocks = 0;
+    while parse_deflate_block(&mut deflate.blocks, data, window, settings)? {
+        data.drop_blocks(&mut deflate.blocks);
+    }
+    data.align(&mut deflate.padding)?;
+    data.drop_blocks(&mut deflate.blocks);
     Ok(())
 }
 
@@ -807,63 +1203,467 @@ fn parse_zlib(
     window: &mut Window,
     settings: &Settings,
 ) -> Result<(), Error> {
-    data.pop_le(&mut zlib.cmf)?;
-    let flg = data.pop_le(&mut zlib.flg)?;
+    // 2.2. Data format
+    let cmf = data.pop_le(&mut zlib.cmf)?.clone();
+    let flg = data.pop_le(&mut zlib.flg)?.clone();
+    // CM = 8 denotes the "deflate" compression method
+    if cmf.v & 0xf != 8 {
+        zlib.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: cmf.start,
+            msg: format!("CM={}", cmf.v & 0xf),
+            context: None,
+        });
+    }
+    // CINFO values above 7 are not allowed in this version of the specification
+    if cmf.v >> 4 > 7 {
+        zlib.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: cmf.start,
+            msg: format!("CINFO={}", cmf.v >> 4),
+            context: None,
+        });
+    }
+    // CMF*256 + FLG must be a multiple of 31
+    let fcheck = (u16::from(cmf.v) * 256 + u16::from(flg.v)) % 31;
+    if fcheck != 0 {
+        zlib.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: flg.start,
+            msg: format!("FCHECK (remainder={})", fcheck),
+            context: None,
+        });
+    }
+    // FDICT (Preset dictionary)
     if flg.v & 0x20 != 0 {
-        data.pop_le(&mut zlib.dictid)?;
+        let dictid = data.pop_be(&mut zlib.dictid)?;
+        match window.dictionary_adler32 {
+            Some(dictionary_adler32) => {
+                zlib.dictid_computed = Some(dictionary_adler32);
+                if dictid.v != dictionary_adler32 {
+                    zlib.warnings.push(ParseError {
+                        kind: ErrorKind::ChecksumMismatch,
+                        pos: dictid.start,
+                        msg: format!(
+                            "DICTID (dictionary has Adler-32 0x{:08x}, expected 0x{:08x})",
+                            dictionary_adler32, dictid.v
+                        ),
+                        context: None,
+                    });
+                }
+            }
+            None => zlib.warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: flg.start,
+                msg: format!(
+                    "FDICT is set, but no dictionary is given (DICTID=0x{:08x})",
+                    dictid.v
+                ),
+                context: None,
+            }),
+        }
+    } else if window.dictionary_adler32.is_some() {
+        zlib.warnings.push(ParseError {
+            kind: ErrorKind::Inconsistent,
+            pos: flg.start,
+            msg: String::from("Dictionary is given, but FDICT is not set"),
+            context: None,
+        });
     }
+    data.visitor
+        .on_container_header(ContainerHeader::Zlib(zlib))?;
     zlib.deflate = Some(DeflateStream::default());
+    window.adler32 = Adler32::default();
     match &mut zlib.deflate {
         Some(deflate) => parse_deflate(deflate, data, window, settings)?,
         None => unreachable!(),
     }
-    data.pop_le(&mut zlib.adler32)?;
+    let adler32_computed = window.adler32.value();
+    zlib.adler32_computed = Some(adler32_computed);
+    let adler32 = data.pop_be(&mut zlib.adler32)?;
+    if adler32.v != adler32_computed {
+        zlib.warnings.push(ParseError {
+            kind: ErrorKind::ChecksumMismatch,
+            pos: adler32.start,
+            msg: format!("ADLER32 (computed=0x{:08x})", adler32_computed),
+            context: None,
+        });
+    }
     Ok(())
 }
 
-fn parse_gzip(
-    out: &mut Option<CompressedStream>,
+fn parse_gzip_extra(extra: &mut GzipExtra, data: &mut DataStream) -> Result<(), Error> {
+    // 2.3.1.1. Extra field
+    let xlen = data.pop_le(&mut extra.xlen)?;
+    let end = xlen.end + xlen.v as usize * 8;
+    data.require(end - data.pos)?;
+    while data.pos + 32 <= end {
+        extra.subfields.push(GzipExtraSubfield {
+            si1: None,
+            si2: None,
+            len: None,
+            data: None,
+        });
+        let subfield = match extra.subfields.last_mut() {
+            Some(x) => x,
+            None => unreachable!(),
+        };
+        data.pop_le(&mut subfield.si1)?;
+        data.pop_le(&mut subfield.si2)?;
+        let len = data.pop_le(&mut subfield.len)?;
+        if len.end + len.v as usize * 8 > end {
+            return Err(data.parse_error(ErrorKind::BadHeader, "Extra subfield LEN"));
+        }
+        let len_usize = len.v as usize;
+        data.pop_hex(&mut subfield.data, len_usize)?;
+    }
+    if data.pos != end {
+        return Err(data.parse_error(ErrorKind::BadHeader, "Extra subfield header"));
+    }
+    Ok(())
+}
+
+// The BGZF end-of-file marker: an empty BGZF block
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn parse_bgzf_subfield(extra: &GzipExtra, data: &DataStream) -> Option<BgzfBlock> {
+    // SAMv1 4.1. The BGZF compression format: SI1=66, SI2=67, SLEN=2, BSIZE
+    extra.subfields.iter().find_map(|subfield| {
+        match (&subfield.si1, &subfield.si2, &subfield.len, &subfield.data) {
+            (Some(si1), Some(si2), Some(len), Some(bsize))
+                if si1.v == 66 && si2.v == 67 && len.v == 2 =>
+            {
+                let index = bsize.start / 8;
+                Some(BgzfBlock {
+                    bsize: Value {
+                        v: u16::from(data.bytes[index - data.base])
+                            | (u16::from(data.bytes[index + 1 - data.base]) << 8),
+                        start: bsize.start,
+                        end: bsize.end,
+                    },
+                    bsize_computed: None,
+                    eof: false,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+fn check_bgzf_block(bgzf: &mut BgzfBlock, bytes: &[u8], warnings: &mut Vec<ParseError>) {
+    // BSIZE is the total block size minus 1
+    let bsize_computed = bytes.len() - 1;
+    bgzf.bsize_computed = Some(bsize_computed);
+    if bgzf.bsize.v as usize != bsize_computed {
+        warnings.push(ParseError {
+            kind: ErrorKind::SizeMismatch,
+            pos: bgzf.bsize.start,
+            msg: format!("BSIZE (computed={})", bsize_computed),
+            context: None,
+        });
+    }
+    bgzf.eof = bytes == BGZF_EOF;
+}
+
+fn parse_gzip_member(
+    out: &mut Vec<GzipStream>,
     data: &mut DataStream,
     window: &mut Window,
     settings: &Settings,
 ) -> Result<(), Error> {
+    // 2.3. Member format
     let magic = data.peek_le::<u16>()?;
     if magic.v == 0x8b1f {
+        let header_start = data.byte_index()?;
         data.drop(16)?;
-        *out = Some(CompressedStream::Gzip(Box::new(GzipStream {
+        out.push(GzipStream {
             magic,
             method: None,
             flags: None,
             time: None,
             xflags: None,
             os: None,
+            extra: None,
             name: None,
+            comment: None,
+            header_crc: None,
+            header_crc_computed: None,
             deflate: None,
             checksum: None,
+            checksum_computed: None,
             len: None,
-        })));
-        let gzip = match out {
-            Some(CompressedStream::Gzip(x)) => x,
-            _ => unreachable!(),
+            len_computed: None,
+            bgzf: None,
+            warnings: Vec::new(),
+        });
+        let gzip = match out.last_mut() {
+            Some(x) => x,
+            None => unreachable!(),
         };
-        data.pop_le(&mut gzip.method)?;
-        let flags = data.pop_le(&mut gzip.flags)?.v;
+        let method = data.pop_le(&mut gzip.method)?;
+        if method.v != 8 {
+            gzip.warnings.push(ParseError {
+                kind: ErrorKind::BadHeader,
+                pos: method.start,
+                msg: format!("CM={}", method.v),
+                context: None,
+            });
+        }
+        let flags = data.pop_le(&mut gzip.flags)?.clone();
+        // Reserved FLG bits must be zero
+        if flags.v & 0xe0 != 0 {
+            gzip.warnings.push(ParseError {
+                kind: ErrorKind::BadHeader,
+                pos: flags.start,
+                msg: format!("Reserved FLG bits (0x{:02x})", flags.v & 0xe0),
+                context: None,
+            });
+        }
         data.pop_le(&mut gzip.time)?;
         data.pop_le(&mut gzip.xflags)?;
         data.pop_le(&mut gzip.os)?;
-        if flags & 8 != 0 {
+        // FEXTRA
+        if flags.v & 4 != 0 {
+            gzip.extra = Some(GzipExtra {
+                xlen: None,
+                subfields: Vec::new(),
+            });
+            match &mut gzip.extra {
+                Some(extra) => {
+                    parse_gzip_extra(extra, data)?;
+                    gzip.bgzf = parse_bgzf_subfield(extra, data);
+                }
+                None => unreachable!(),
+            }
+        }
+        // FNAME
+        if flags.v & 8 != 0 {
             data.pop_str(&mut gzip.name)?;
         }
+        // FCOMMENT
+        if flags.v & 16 != 0 {
+            data.pop_str(&mut gzip.comment)?;
+        }
+        // FHCRC: the two least significant bytes of the CRC32 for all bytes of the gzip header
+        if flags.v & 2 != 0 {
+            let header_end = data.byte_index()?;
+            let header_crc_computed =
+                crc32(&data.bytes[header_start - data.base..header_end - data.base]) as u16;
+            gzip.header_crc_computed = Some(header_crc_computed);
+            let header_crc = data.pop_le(&mut gzip.header_crc)?;
+            if header_crc.v != header_crc_computed {
+                gzip.warnings.push(ParseError {
+                    kind: ErrorKind::ChecksumMismatch,
+                    pos: header_crc.start,
+                    msg: format!("CRC16 (computed=0x{:04x})", header_crc_computed),
+                    context: None,
+                });
+            }
+        }
+        if gzip.bgzf.is_some() {
+            // BSIZE is checked against the whole member, which is at most 64 KiB
+            data.retain = Some(header_start);
+        }
+        data.visitor
+            .on_container_header(ContainerHeader::Gzip(gzip))?;
         gzip.deflate = Some(DeflateStream::default());
+        let plain_start = window.plain_pos;
+        window.crc32 = Crc32::default();
         match &mut gzip.deflate {
             Some(deflate) => parse_deflate(deflate, data, window, settings)?,
             None => unreachable!(),
         }
-        data.pop_le(&mut gzip.checksum)?;
-        data.pop_le(&mut gzip.len)?;
+        let checksum_computed = window.crc32.value();
+        gzip.checksum_computed = Some(checksum_computed);
+        // ISIZE is the size of the original input data modulo 2^32
+        let len_computed = (window.plain_pos - plain_start) as u32;
+        gzip.len_computed = Some(len_computed);
+        let checksum = data.pop_le(&mut gzip.checksum)?;
+        if checksum.v != checksum_computed {
+            gzip.warnings.push(ParseError {
+                kind: ErrorKind::ChecksumMismatch,
+                pos: checksum.start,
+                msg: format!("CRC32 (computed=0x{:08x})", checksum_computed),
+                context: None,
+            });
+        }
+        let len = data.pop_le(&mut gzip.len)?;
+        if len.v != len_computed {
+            gzip.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: len.start,
+                msg: format!("ISIZE (computed={})", len_computed),
+                context: None,
+            });
+        }
+        if let Some(bgzf) = &mut gzip.bgzf {
+            let member_end = data.byte_index()?;
+            check_bgzf_block(
+                bgzf,
+                &data.bytes[header_start - data.base..member_end - data.base],
+                &mut gzip.warnings,
+            );
+            data.retain = None;
+        }
         Ok(())
     } else {
-        Err(data.parse_error("Stream type"))
+        Err(data.parse_error(ErrorKind::BadHeader, "Stream type"))
+    }
+}
+
+fn parse_gzip(
+    gzip: &mut GzipFile,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 2.2. File format: a gzip file consists of a series of "members"
+    let magic = data.peek_le::<u16>()?;
+    if magic.v != 0x8b1f {
+        return Err(data.parse_error(ErrorKind::BadHeader, "Stream type"));
+    }
+    loop {
+        let compressed = data.pos / 8;
+        let uncompressed = window.plain_pos;
+        parse_gzip_member(&mut gzip.members, data, window, settings)?;
+        if let Some(GzipStream { bgzf: Some(_), .. }) = gzip.members.last() {
+            gzip.bgzf_offsets
+                .get_or_insert_with(Vec::new)
+                .push(BgzfOffset {
+                    compressed,
+                    uncompressed,
+                });
+        }
+        match data.peek_le::<u16>() {
+            Ok(magic) if magic.v == 0x8b1f => window.reset(),
+            _ => break Ok(()),
+        }
+    }
+}
+
+fn detect_format(data: &DataStream) -> DetectedStream {
+    let detected = |format: &str, reason: String, stream: CompressedStream| DetectedStream {
+        format: String::from(format),
+        reason,
+        stream,
+    };
+    if !data.pos.is_multiple_of(8) {
+        return detected(
+            "raw",
+            String::from("Bit offset is not byte-aligned"),
+            CompressedStream::Raw(DeflateStream::default()),
+        );
+    }
+    let bytes = data
+        .bytes
+        .get(data.pos / 8 - data.base..)
+        .unwrap_or_default();
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        detected(
+            "gzip",
+            String::from("ID1=0x1f ID2=0x8b"),
+            CompressedStream::Gzip(GzipFile {
+                members: Vec::new(),
+                bgzf_offsets: None,
+            }),
+        )
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        detected(
+            "png",
+            String::from("PNG signature"),
+            CompressedStream::Png(Box::default()),
+        )
+    } else if bytes.starts_with(b"PK\x03\x04") {
+        detected(
+            "zip",
+            String::from("Local file header signature"),
+            CompressedStream::Zip(Box::default()),
+        )
+    } else if bytes.starts_with(b"%PDF-") {
+        detected(
+            "pdf",
+            String::from("%PDF- header"),
+            CompressedStream::Pdf(Box::default()),
+        )
+    } else if bytes.starts_with(b"PACK") {
+        detected(
+            "git",
+            String::from("Packfile signature"),
+            CompressedStream::Git(Box::default()),
+        )
+    } else {
+        match bytes {
+            // RFC 1950 2.2. Data format
+            [cmf, flg, ..]
+                if cmf & 0xf == 8
+                    && cmf >> 4 <= 7
+                    && (u16::from(*cmf) * 256 + u16::from(*flg)) % 31 == 0 =>
+            {
+                detected(
+                    "zlib",
+                    format!(
+                        "CMF=0x{:02x} FLG=0x{:02x} (CM=8, CINFO={}, FCHECK is valid)",
+                        cmf,
+                        flg,
+                        cmf >> 4
+                    ),
+                    CompressedStream::Zlib(ZlibStream::default()),
+                )
+            }
+            _ => detected(
+                "raw",
+                String::from("No known signature"),
+                CompressedStream::Raw(DeflateStream::default()),
+            ),
+        }
+    }
+}
+
+fn parse_compressed_stream(
+    out: &mut CompressedStream,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    match out {
+        CompressedStream::Raw(deflate) => parse_deflate(deflate, data, window, settings),
+        CompressedStream::Gzip(gzip) => parse_gzip(gzip, data, window, settings),
+        CompressedStream::Dht(dht) => parse_dht(dht, data),
+        CompressedStream::Zlib(zlib) => parse_zlib(zlib, data, window, settings),
+        CompressedStream::Zip(zip) => {
+            data.read_to_end()?;
+            zip::parse_zip(zip, data, window, settings)
+        }
+        CompressedStream::Png(png) => {
+            data.read_to_end()?;
+            png::parse_png(png, data, window, settings)
+        }
+        CompressedStream::Pdf(pdf) => {
+            data.read_to_end()?;
+            pdf::parse_pdf(pdf, data, window, settings)
+        }
+        CompressedStream::Git(git) => {
+            data.read_to_end()?;
+            git::parse_git(git, data, window, settings)
+        }
+        CompressedStream::WebSocket(session) => {
+            data.read_to_end()?;
+            websocket::parse_websocket(session, data, window, settings)
+        }
+        CompressedStream::Detected(detected) => {
+            parse_compressed_stream(&mut detected.stream, data, window, settings)
+        }
+        CompressedStream::Scan(scan) => {
+            data.read_to_end()?;
+            // Most of the attempts fail, so the scan is reported only through the model
+            let visitor = std::mem::replace(&mut data.visitor, Box::new(Model));
+            let result = scan::scan(scan, data, window, settings);
+            data.visitor = visitor;
+            result
+        }
     }
 }
 
@@ -873,22 +1673,42 @@ fn parse_data_stream(
     window: &mut Window,
     settings: &Settings,
 ) -> Result<(), Error> {
-    match out {
-        Some(CompressedStream::Raw(deflate)) => parse_deflate(deflate, &mut data, window, settings),
-        Some(CompressedStream::Dht(dht)) => parse_dht(dht, &mut data),
-        Some(CompressedStream::Zlib(zlib)) => parse_zlib(zlib, &mut data, window, settings),
-        _ => parse_gzip(out, &mut data, window, settings),
-    }?;
-    if data.pos == data.end {
-        Ok(())
-    } else {
-        Err(data.parse_error(&format!("Garbage (end={})", data.end)))
+    let stream = match out {
+        Some(stream) => stream,
+        None => {
+            // The longest signature is the 8-byte PNG one
+            data.fill_to(64)?;
+            out.insert(CompressedStream::Detected(Box::new(detect_format(&data))))
+        }
+    };
+    // Without a signature, the input is only assumed to be raw deflate, so say so if it is not
+    let fallback = match stream {
+        CompressedStream::Detected(detected) if detected.format == "raw" => {
+            Some(detected.reason.clone())
+        }
+        _ => None,
+    };
+    let result = parse_compressed_stream(stream, &mut data, window, settings).and_then(|()| {
+        if data.at_end()? {
+            Ok(())
+        } else {
+            data.read_to_end()?;
+            Err(data.parse_error(ErrorKind::Garbage, &format!("Garbage (end={})", data.end)))
+        }
+    });
+    match (result, fallback) {
+        (Err(Error::Parse(mut err)), Some(reason)) => {
+            err.msg = format!("{} (parsed as raw deflate: {})", err.msg, reason);
+            Err(Error::from(err))
+        }
+        (result, _) => result,
     }
 }
 
 pub struct Settings {
     pub bit_offset: usize,
     pub data: bool,
+    pub deflate64: bool,
 }
 
 pub fn parse(
@@ -897,24 +1717,97 @@ pub fn parse(
     window: &mut Window,
     settings: &Settings,
 ) -> Result<(), Error> {
+    if let Some(CompressedStream::WebSocket(session)) = out {
+        if path.is_dir() {
+            return websocket::parse_websocket_directory(session, path, window, settings);
+        }
+    }
     let data = DataStream::new(path, settings.bit_offset)?;
     parse_data_stream(out, data, window, settings)
 }
 
-fn get_blocks(stream: &CompressedStream) -> &[DeflateBlock] {
+/// Like `parse`, but for data that is already in memory. Positions are relative to the start of
+/// `bytes`.
+pub fn parse_bytes(
+    out: &mut Option<CompressedStream>,
+    bytes: &[u8],
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let data = DataStream::from_bytes(bytes.to_vec(), settings.bit_offset);
+    parse_data_stream(out, data, window, settings)
+}
+
+/// Like `parse`, but reads the data from `reader` until EOF first.
+pub fn parse_reader<R: Read>(
+    out: &mut Option<CompressedStream>,
+    reader: &mut R,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let data = DataStream::from_bytes(bytes, settings.bit_offset);
+    parse_data_stream(out, data, window, settings)
+}
+
+/// Like `parse_reader`, but reports the progress to `visitor`. Unless the visitor keeps the
+/// blocks, only as much of the data is kept in memory as the current block needs, and the blocks
+/// are not kept in `out`. Formats that need random access to the data (zip, png, pdf, git,
+/// websocket and scan) are read into memory and their blocks are kept in `out` as well. Scan
+/// results are reported only through `out`.
+pub fn parse_streaming<R: Read, V: ParseVisitor>(
+    out: &mut Option<CompressedStream>,
+    reader: R,
+    window: &mut Window,
+    settings: &Settings,
+    visitor: V,
+) -> Result<(), Error> {
+    let data = DataStream::from_reader(reader, settings.bit_offset, visitor);
+    parse_data_stream(out, data, window, settings)
+}
+
+pub fn get_deflate_streams(stream: &CompressedStream) -> Vec<&DeflateStream> {
     match &stream {
-        CompressedStream::Raw(deflate_stream) => &deflate_stream.blocks,
-        CompressedStream::Gzip(gzip_stream) => gzip_stream
-            .deflate
-            .as_ref()
-            .map(|deflate| deflate.blocks.as_slice())
-            .unwrap_or_else(|| &[]),
-        CompressedStream::Dht(_) => &[],
-        CompressedStream::Zlib(zlib_stream) => zlib_stream
-            .deflate
-            .as_ref()
-            .map(|deflate| deflate.blocks.as_slice())
-            .unwrap_or_else(|| &[]),
+        CompressedStream::Raw(deflate_stream) => vec![deflate_stream],
+        CompressedStream::Gzip(gzip_file) => gzip_file
+            .members
+            .iter()
+            .filter_map(|member| member.deflate.as_ref())
+            .collect(),
+        CompressedStream::Dht(_) => vec![],
+        CompressedStream::Zlib(zlib_stream) => zlib_stream.deflate.iter().collect(),
+        CompressedStream::Zip(zip_archive) => zip_archive
+            .entries
+            .iter()
+            .filter_map(|entry| entry.deflate.as_ref())
+            .collect(),
+        CompressedStream::Png(png_image) => png_image
+            .streams
+            .iter()
+            .filter_map(|stream| stream.zlib.deflate.as_ref())
+            .collect(),
+        CompressedStream::Pdf(pdf_document) => pdf_document
+            .objects
+            .iter()
+            .filter_map(|object| object.zlib.as_ref()?.deflate.as_ref())
+            .collect(),
+        CompressedStream::Git(git_file) => git_file
+            .objects
+            .iter()
+            .filter_map(|object| object.zlib.as_ref()?.deflate.as_ref())
+            .collect(),
+        CompressedStream::WebSocket(session) => session
+            .messages
+            .iter()
+            .map(|message| &message.deflate)
+            .collect(),
+        CompressedStream::Detected(detected) => get_deflate_streams(&detected.stream),
+        CompressedStream::Scan(scan) => scan
+            .hits
+            .iter()
+            .flat_map(|hit| get_deflate_streams(&hit.stream))
+            .collect(),
     }
 }
 
@@ -940,20 +1833,52 @@ fn write_tokens(file: &mut File, tokens: &Option<Vec<Value<Token>>>) -> Result<(
     Ok(())
 }
 
-pub fn write_data(file: &mut File, stream: &Option<CompressedStream>) -> Result<(), Error> {
-    if let Some(stream) = &stream {
-        for block in get_blocks(stream) {
-            match &block.ext {
-                Some(DeflateBlockExt::Stored(stored)) => {
-                    if let Some(data) = &stored.data {
-                        file.write_all(&hex::decode(&data.v)?)?;
-                    }
+pub fn write_block_data(file: &mut File, block: &DeflateBlock) -> Result<(), Error> {
+    match &block.ext {
+        Some(DeflateBlockExt::Stored(stored)) => {
+            if let Some(data) = &stored.data {
+                file.write_all(&hex::decode(&data.v)?)?;
+            }
+        }
+        Some(DeflateBlockExt::Fixed(fixed)) => write_tokens(file, &fixed.tokens)?,
+        Some(DeflateBlockExt::Dynamic(dynamic)) => write_tokens(file, &dynamic.tokens)?,
+        None => {}
+    }
+    Ok(())
+}
+
+fn write_deflate_data(file: &mut File, deflate: &DeflateStream) -> Result<(), Error> {
+    for block in &deflate.blocks {
+        write_block_data(file, block)?;
+    }
+    Ok(())
+}
+
+fn write_stream_data(file: &mut File, stream: &CompressedStream) -> Result<(), Error> {
+    match stream {
+        CompressedStream::Zip(zip_archive) => {
+            for entry in &zip_archive.entries {
+                if let Some(data) = &entry.stored {
+                    file.write_all(&hex::decode(&data.v)?)?;
                 }
-                Some(DeflateBlockExt::Fixed(fixed)) => write_tokens(file, &fixed.tokens)?,
-                Some(DeflateBlockExt::Dynamic(dynamic)) => write_tokens(file, &dynamic.tokens)?,
-                None => {}
+                if let Some(deflate) = &entry.deflate {
+                    write_deflate_data(file, deflate)?;
+                }
+            }
+        }
+        CompressedStream::Detected(detected) => write_stream_data(file, &detected.stream)?,
+        stream => {
+            for deflate in get_deflate_streams(stream) {
+                write_deflate_data(file, deflate)?;
             }
         }
     }
     Ok(())
 }
+
+pub fn write_data(file: &mut File, stream: &Option<CompressedStream>) -> Result<(), Error> {
+    if let Some(stream) = stream {
+        write_stream_data(file, stream)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,669 @@
+use std::collections::HashMap;
+
+use crate::data::{PdfDocument, PdfObject, PdfPredictor, PdfPredictorRow, Value, ZlibStream};
+use crate::error::{Error, ErrorKind, ParseError};
+use crate::{parse_zlib, DataStream, Settings, Window};
+
+/// The subset of ISO 32000-1 7.3 Objects that is needed to locate and decode streams.
+enum PdfValue {
+    Integer(i64),
+    Name(String),
+    Reference(u32, u16),
+    Array(Vec<PdfValue>),
+    Dictionary(Vec<(String, PdfValue)>),
+    Other,
+}
+
+impl PdfValue {
+    fn get(&self, key: &str) -> Option<&PdfValue> {
+        match self {
+            PdfValue::Dictionary(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+// 7.2.2 Character set
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte) && !is_delimiter(byte)
+}
+
+fn find(bytes: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|index| from + index)
+}
+
+struct Lexer<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &[u8]) -> bool {
+        self.bytes
+            .get(self.pos..)
+            .is_some_and(|bytes| bytes.starts_with(s))
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(byte) if is_whitespace(byte) => self.pos += 1,
+                // 7.2.3 Comments
+                Some(b'%') => {
+                    while self
+                        .peek()
+                        .is_some_and(|byte| byte != b'\r' && byte != b'\n')
+                    {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn regular(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(is_regular) {
+            self.pos += 1;
+        }
+        &self.bytes[start..self.pos]
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        std::str::from_utf8(self.regular()).ok()?.parse().ok()
+    }
+
+    fn name(&mut self) -> String {
+        // 7.3.5 Name Objects
+        self.pos += 1;
+        let token = self.regular();
+        let mut name = String::new();
+        let mut i = 0;
+        while i < token.len() {
+            let escaped = token
+                .get(i + 1..i + 3)
+                .filter(|_| token[i] == b'#')
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    name.push(char::from(byte));
+                    i += 3;
+                }
+                None => {
+                    name.push(char::from(token[i]));
+                    i += 1;
+                }
+            }
+        }
+        name
+    }
+
+    fn literal_string(&mut self) -> Option<()> {
+        // 7.3.4.2 Literal Strings
+        let mut depth = 0;
+        loop {
+            match self.peek()? {
+                b'\\' => self.pos += 1,
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return Some(());
+                    }
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Option<PdfValue> {
+        self.skip_whitespace();
+        match self.peek()? {
+            // 7.3.7 Dictionary Objects
+            b'<' if self.starts_with(b"<<") => {
+                self.pos += 2;
+                let mut entries = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.starts_with(b">>") {
+                        self.pos += 2;
+                        return Some(PdfValue::Dictionary(entries));
+                    }
+                    if self.peek()? != b'/' {
+                        return None;
+                    }
+                    let key = self.name();
+                    let value = self.value()?;
+                    entries.push((key, value));
+                }
+            }
+            // 7.3.4.3 Hexadecimal Strings
+            b'<' => {
+                self.pos = find(self.bytes, b">", self.pos)? + 1;
+                Some(PdfValue::Other)
+            }
+            // 7.3.6 Array Objects
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.peek()? == b']' {
+                        self.pos += 1;
+                        return Some(PdfValue::Array(items));
+                    }
+                    items.push(self.value()?);
+                }
+            }
+            b'(' => {
+                self.literal_string()?;
+                Some(PdfValue::Other)
+            }
+            b'/' => Some(PdfValue::Name(self.name())),
+            _ => {
+                let token = self.regular();
+                if token.is_empty() {
+                    return None;
+                }
+                let number = match std::str::from_utf8(token).ok()?.parse::<i64>() {
+                    Ok(number) => number,
+                    Err(_) => return Some(PdfValue::Other),
+                };
+                // 7.3.10 Indirect Objects: 12 0 R
+                let end = self.pos;
+                self.skip_whitespace();
+                if let Some(generation) = self.integer() {
+                    self.skip_whitespace();
+                    if self.regular() == b"R" {
+                        if let (Ok(number), Ok(generation)) =
+                            (u32::try_from(number), u16::try_from(generation))
+                        {
+                            return Some(PdfValue::Reference(number, generation));
+                        }
+                    }
+                }
+                self.pos = end;
+                Some(PdfValue::Integer(number))
+            }
+        }
+    }
+}
+
+/// Finds the next `12 0 obj` header, returning the object number, the generation number, the
+/// offset of the header and the offset of the object body.
+fn find_object(bytes: &[u8], from: usize) -> Option<(u32, u16, usize, usize)> {
+    let mut pos = from;
+    loop {
+        let index = find(bytes, b"obj", pos)?;
+        pos = index + 1;
+        if bytes.get(index + 3).is_some_and(|byte| is_regular(*byte)) {
+            continue;
+        }
+        let skip_back = |end: usize, predicate: fn(&u8) -> bool| {
+            end - bytes[..end]
+                .iter()
+                .rev()
+                .take_while(|x| predicate(x))
+                .count()
+        };
+        let generation_end = skip_back(index, |byte| is_whitespace(*byte));
+        let generation_start = skip_back(generation_end, u8::is_ascii_digit);
+        let number_end = skip_back(generation_start, |byte| is_whitespace(*byte));
+        let number_start = skip_back(number_end, u8::is_ascii_digit);
+        if generation_end == index
+            || generation_start == generation_end
+            || number_end == generation_start
+            || number_start == number_end
+            || number_start
+                .checked_sub(1)
+                .is_some_and(|prev| is_regular(bytes[prev]))
+        {
+            continue;
+        }
+        let parse = |start: usize, end: usize| std::str::from_utf8(&bytes[start..end]).ok();
+        let number = parse(number_start, number_end).and_then(|x| x.parse().ok());
+        let generation = parse(generation_start, generation_end).and_then(|x| x.parse().ok());
+        if let (Some(number), Some(generation)) = (number, generation) {
+            return Some((number, generation, number_start, index + 3));
+        }
+    }
+}
+
+struct StreamObject {
+    number: u32,
+    generation: u16,
+    offset: usize,
+    dictionary: PdfValue,
+    start: usize,
+    endstream: Option<usize>,
+}
+
+fn find_stream_objects(
+    bytes: &[u8],
+    start: usize,
+) -> (Vec<StreamObject>, HashMap<(u32, u16), i64>) {
+    let mut objects = Vec::new();
+    let mut integers = HashMap::new();
+    let mut pos = start;
+    while let Some((number, generation, offset, body)) = find_object(bytes, pos) {
+        let mut lexer = Lexer { bytes, pos: body };
+        let value = lexer.value();
+        pos = lexer.pos.max(body);
+        match value {
+            Some(PdfValue::Integer(value)) => {
+                integers.insert((number, generation), value);
+            }
+            Some(dictionary @ PdfValue::Dictionary(_)) => {
+                // 7.3.8.1 Stream Objects: The keyword stream that follows the stream dictionary
+                // shall be followed by an end-of-line marker consisting of either a CARRIAGE
+                // RETURN and a LINE FEED or just a LINE FEED, and not by a CARRIAGE RETURN alone.
+                lexer.skip_whitespace();
+                if !lexer.starts_with(b"stream") {
+                    continue;
+                }
+                lexer.pos += 6;
+                if lexer.starts_with(b"\r\n") {
+                    lexer.pos += 2;
+                } else if lexer.starts_with(b"\n") || lexer.starts_with(b"\r") {
+                    lexer.pos += 1;
+                }
+                let endstream = find(bytes, b"endstream", lexer.pos);
+                pos = endstream.map_or(bytes.len(), |endstream| endstream + 9);
+                objects.push(StreamObject {
+                    number,
+                    generation,
+                    offset,
+                    dictionary,
+                    start: lexer.pos,
+                    endstream,
+                });
+            }
+            _ => {}
+        }
+    }
+    (objects, integers)
+}
+
+fn names(value: Option<&PdfValue>) -> Vec<String> {
+    match value {
+        Some(PdfValue::Name(name)) => vec![name.clone()],
+        Some(PdfValue::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                PdfValue::Name(name) => name.clone(),
+                _ => String::new(),
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn ends_at_endstream(bytes: &[u8], end: usize) -> bool {
+    // There should be an end-of-line marker after the data and before endstream
+    let mut lexer = Lexer { bytes, pos: end };
+    while lexer.peek().is_some_and(is_whitespace) {
+        lexer.pos += 1;
+    }
+    lexer.starts_with(b"endstream")
+}
+
+fn stream_end(
+    object: &mut PdfObject,
+    stream: &StreamObject,
+    integers: &HashMap<(u32, u16), i64>,
+    bytes: &[u8],
+) -> usize {
+    let pos = stream.start * 8;
+    let length = match stream.dictionary.get("Length") {
+        Some(PdfValue::Integer(length)) => Some(*length),
+        Some(PdfValue::Reference(number, generation)) => {
+            let length = integers.get(&(*number, *generation)).copied();
+            if length.is_none() {
+                object.warnings.push(ParseError {
+                    kind: ErrorKind::Inconsistent,
+                    pos,
+                    msg: format!("/Length {} {} R is not found", number, generation),
+                    context: None,
+                });
+            }
+            length
+        }
+        _ => {
+            object.warnings.push(ParseError {
+                kind: ErrorKind::BadHeader,
+                pos,
+                msg: String::from("/Length is missing"),
+                context: None,
+            });
+            None
+        }
+    };
+    object.length = length.and_then(|length| usize::try_from(length).ok());
+    if let Some(length) = object.length {
+        let end = stream.start.saturating_add(length);
+        if end <= bytes.len() && ends_at_endstream(bytes, end) {
+            return end;
+        }
+    }
+    match stream.endstream {
+        Some(endstream) => {
+            let mut end = endstream;
+            if bytes[..end].ends_with(b"\r\n") {
+                end -= 2;
+            } else if bytes[..end].ends_with(b"\n") || bytes[..end].ends_with(b"\r") {
+                end -= 1;
+            }
+            let end = end.max(stream.start);
+            if let Some(length) = object.length {
+                object.warnings.push(ParseError {
+                    kind: ErrorKind::SizeMismatch,
+                    pos,
+                    msg: format!("/Length={} (endstream at {})", length, end - stream.start),
+                    context: None,
+                });
+            }
+            end
+        }
+        None => {
+            object.warnings.push(ParseError {
+                kind: ErrorKind::Eof,
+                pos,
+                msg: String::from("endstream is not found"),
+                context: None,
+            });
+            bytes.len()
+        }
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let pa = (p - i16::from(a)).abs();
+    let pb = (p - i16::from(b)).abs();
+    let pc = (p - i16::from(c)).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn undo_predictor(
+    predictor: &mut PdfPredictor,
+    plain: &[u8],
+    pos: usize,
+    warnings: &mut Vec<ParseError>,
+    settings: &Settings,
+) {
+    // 7.4.4.4 LZW and Flate Predictor Functions
+    let bits_per_pixel = predictor.colors as usize * predictor.bits_per_component as usize;
+    let bpp = bits_per_pixel.div_ceil(8).max(1);
+    // Rows longer than the data come from a corrupt dictionary, do not allocate them
+    let row_len = match bits_per_pixel
+        .checked_mul(predictor.columns as usize)
+        .map(|bits| bits.div_ceil(8))
+        .filter(|row_len| *row_len <= plain.len())
+    {
+        Some(row_len) => row_len,
+        None => {
+            warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos,
+                msg: format!(
+                    "Predictor row size (Colors={}, BitsPerComponent={}, Columns={}, len={})",
+                    predictor.colors,
+                    predictor.bits_per_component,
+                    predictor.columns,
+                    plain.len()
+                ),
+                context: None,
+            });
+            return;
+        }
+    };
+    let mut out: Vec<u8> = Vec::with_capacity(plain.len());
+    match predictor.predictor {
+        // TIFF Predictor 2
+        2 if predictor.bits_per_component == 8 => {
+            for row in plain.chunks(row_len.max(1)) {
+                let row_start = out.len();
+                for (i, byte) in row.iter().enumerate() {
+                    let left = if i >= bpp {
+                        out[row_start + i - bpp]
+                    } else {
+                        0
+                    };
+                    out.push(byte.wrapping_add(left));
+                }
+            }
+        }
+        // PNG prediction: each row starts with a filter-type byte
+        10..=15 => {
+            let mut prev = vec![0u8; row_len];
+            for (index, row) in plain.chunks(row_len + 1).enumerate() {
+                let filter = row[0];
+                predictor.rows.push(PdfPredictorRow {
+                    plain_pos: index * (row_len + 1),
+                    filter,
+                });
+                if row.len() != row_len + 1 {
+                    warnings.push(ParseError {
+                        kind: ErrorKind::SizeMismatch,
+                        pos,
+                        msg: format!("Predictor row size (expected={})", row_len + 1),
+                        context: None,
+                    });
+                }
+                let mut cur = vec![0u8; row_len];
+                for (i, byte) in row[1..].iter().enumerate() {
+                    let a = if i >= bpp { cur[i - bpp] } else { 0 };
+                    let b = prev[i];
+                    let c = if i >= bpp { prev[i - bpp] } else { 0 };
+                    cur[i] = match filter {
+                        1 => byte.wrapping_add(a),
+                        2 => byte.wrapping_add(b),
+                        3 => byte.wrapping_add(((u16::from(a) + u16::from(b)) / 2) as u8),
+                        4 => byte.wrapping_add(paeth(a, b, c)),
+                        _ => *byte,
+                    };
+                }
+                if filter > 4 {
+                    warnings.push(ParseError {
+                        kind: ErrorKind::InvalidCode,
+                        pos,
+                        msg: format!("Predictor filter type={} (row={})", filter, index),
+                        context: None,
+                    });
+                }
+                out.extend_from_slice(&cur[..row.len() - 1]);
+                prev = cur;
+            }
+        }
+        // Only whole bytes are predicted for now
+        2 => {
+            warnings.push(ParseError {
+                kind: ErrorKind::Unsupported,
+                pos,
+                msg: format!(
+                    "TIFF Predictor 2 (BitsPerComponent={})",
+                    predictor.bits_per_component
+                ),
+                context: None,
+            });
+            return;
+        }
+        _ => {
+            warnings.push(ParseError {
+                kind: ErrorKind::Unsupported,
+                pos,
+                msg: format!(
+                    "Predictor={} (BitsPerComponent={})",
+                    predictor.predictor, predictor.bits_per_component
+                ),
+                context: None,
+            });
+            return;
+        }
+    }
+    if settings.data {
+        predictor.data = Some(hex::encode(out));
+    }
+}
+
+fn parse_predictor(parms: Option<&PdfValue>) -> Option<PdfPredictor> {
+    // Table 8 - Optional parameters for LZWDecode and FlateDecode filters
+    let parms = parms?;
+    let get = |key: &str, default: u32| match parms.get(key) {
+        Some(PdfValue::Integer(value)) => u32::try_from(*value).unwrap_or(default),
+        _ => default,
+    };
+    let predictor = get("Predictor", 1);
+    if predictor == 1 {
+        return None;
+    }
+    Some(PdfPredictor {
+        predictor,
+        colors: get("Colors", 1),
+        bits_per_component: get("BitsPerComponent", 8),
+        columns: get("Columns", 1),
+        rows: Vec::new(),
+        data: None,
+    })
+}
+
+fn parse_object(
+    object: &mut PdfObject,
+    stream: &StreamObject,
+    integers: &HashMap<(u32, u16), i64>,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let end = stream_end(object, stream, integers, &data.bytes);
+    let pos = stream.start * 8;
+    object.stream = Some(Value {
+        v: end - stream.start,
+        start: pos,
+        end: end * 8,
+    });
+    // 7.4.1 General: The filters shall be applied in the order given
+    let index = object
+        .filters
+        .iter()
+        .position(|filter| filter == "FlateDecode" || filter == "Fl")
+        .unwrap_or(0);
+    if index != 0 {
+        object.warnings.push(ParseError {
+            kind: ErrorKind::Unsupported,
+            pos,
+            msg: format!("FlateDecode is preceded by /{}", object.filters[0]),
+            context: None,
+        });
+        return Ok(());
+    }
+    let parms = match stream.dictionary.get("DecodeParms") {
+        Some(PdfValue::Array(items)) => items.first(),
+        parms => parms,
+    };
+    object.predictor = parse_predictor(parms);
+    let data_end = data.end;
+    data.pos = pos;
+    data.end = end * 8;
+    window.reset();
+    window.plain_pos = 0;
+    window.capture = Some(Vec::new());
+    let zlib = object.zlib.insert(ZlibStream::default());
+    let result = parse_zlib(zlib, data, window, settings);
+    let plain = window.capture.take().unwrap_or_default();
+    match result {
+        Ok(()) if data.pos != data.end => zlib.warnings.push(ParseError {
+            kind: ErrorKind::Garbage,
+            pos: data.pos,
+            msg: format!("Garbage (end={})", data.end),
+            context: None,
+        }),
+        Ok(()) => {}
+        Err(Error::Parse(err)) => object.error = Some(err),
+        Err(err) => return Err(err),
+    }
+    data.pos = data_end;
+    data.end = data_end;
+    if let Some(predictor) = &mut object.predictor {
+        undo_predictor(predictor, &plain, pos, &mut object.warnings, settings);
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_pdf(
+    pdf: &mut PdfDocument,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let start = data.byte_index()?;
+    if !data.bytes[start - data.base..].starts_with(b"%PDF-") {
+        pdf.warnings.push(ParseError {
+            kind: ErrorKind::BadHeader,
+            pos: data.pos,
+            msg: String::from("%PDF- header is missing"),
+            context: None,
+        });
+    }
+    let (streams, integers) = find_stream_objects(&data.bytes, start);
+    for stream in &streams {
+        let filters = names(stream.dictionary.get("Filter"));
+        if !filters
+            .iter()
+            .any(|filter| filter == "FlateDecode" || filter == "Fl")
+        {
+            continue;
+        }
+        pdf.objects.push(PdfObject {
+            number: stream.number,
+            generation: stream.generation,
+            offset: stream.offset,
+            filters,
+            length: None,
+            stream: None,
+            zlib: None,
+            error: None,
+            predictor: None,
+            warnings: Vec::new(),
+        });
+        let object = match pdf.objects.last_mut() {
+            Some(x) => x,
+            None => unreachable!(),
+        };
+        parse_object(object, stream, &integers, data, window, settings)?;
+    }
+    data.pos = data.end;
+    Ok(())
+}
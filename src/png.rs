@@ -0,0 +1,537 @@
+use crate::checksum::crc32;
+use crate::data::{
+    CodeLengthCode, DeflateBlock, DeflateBlockDynamic, DeflateBlockExt, DeflateBlockFixed,
+    DeflateBlockHeader, DeflateBlockStored, DeflateStream, DynamicHuffmanTable, HuffmanCode,
+    Latin1String, PngChunk, PngHeader, PngImage, PngScanline, PngStream, Token, Value, ZlibStream,
+};
+use crate::error::{Error, ErrorKind, ParseError};
+use crate::{parse_zlib, DataStream, Settings, Window};
+
+// 5.2 PNG signature
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+// 8.2 Interlace methods: (starting column, starting row, column increment, row increment)
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// A piece of the logical zlib stream, which lives at `file` in the PNG file.
+struct Segment {
+    logical: usize,
+    file: usize,
+    len: usize,
+}
+
+/// Translates bit positions in the joined IDAT or fdAT payloads back to the PNG file.
+struct PositionMap {
+    segments: Vec<Segment>,
+}
+
+impl PositionMap {
+    fn pos(&self, pos: usize) -> usize {
+        let byte = pos / 8;
+        let index = self
+            .segments
+            .partition_point(|segment| segment.logical + segment.len <= byte);
+        match self.segments.get(index).or(self.segments.last()) {
+            Some(segment) => (segment.file + byte - segment.logical) * 8 + pos % 8,
+            None => pos,
+        }
+    }
+
+    fn end(&self, end: usize) -> usize {
+        match end.checked_sub(1) {
+            Some(last) => self.pos(last) + 1,
+            None => self.pos(end),
+        }
+    }
+}
+
+trait MapPositions {
+    fn map_positions(&mut self, map: &PositionMap);
+}
+
+macro_rules! impl_map_positions_noop {
+    ($($t:ty),*) => {
+        $(impl MapPositions for $t {
+            fn map_positions(&mut self, _map: &PositionMap) {}
+        })*
+    };
+}
+
+impl_map_positions_noop!(u8, u16, u32, String, Latin1String);
+
+impl<T: Clone + MapPositions> MapPositions for Value<T> {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.start = map.pos(self.start);
+        self.end = map.end(self.end);
+        self.v.map_positions(map);
+    }
+}
+
+impl<T: MapPositions> MapPositions for Option<T> {
+    fn map_positions(&mut self, map: &PositionMap) {
+        if let Some(x) = self {
+            x.map_positions(map);
+        }
+    }
+}
+
+impl<T: MapPositions> MapPositions for Vec<T> {
+    fn map_positions(&mut self, map: &PositionMap) {
+        for x in self {
+            x.map_positions(map);
+        }
+    }
+}
+
+impl<T: MapPositions> MapPositions for Box<T> {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.as_mut().map_positions(map);
+    }
+}
+
+impl MapPositions for ParseError {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.pos = map.pos(self.pos);
+        if let Some(context) = &mut self.context {
+            context.block_start = context.block_start.map(|pos| map.pos(pos));
+        }
+    }
+}
+
+impl MapPositions for Error {
+    fn map_positions(&mut self, map: &PositionMap) {
+        if let Error::Parse(err) = self {
+            err.map_positions(map);
+        }
+    }
+}
+
+impl MapPositions for ZlibStream {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.cmf.map_positions(map);
+        self.flg.map_positions(map);
+        self.dictid.map_positions(map);
+        self.deflate.map_positions(map);
+        self.adler32.map_positions(map);
+        self.warnings.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateStream {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.blocks.map_positions(map);
+        self.padding.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateBlock {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.header.map_positions(map);
+        self.end = self.end.map(|end| map.end(end));
+        self.ext.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateBlockHeader {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.bfinal.map_positions(map);
+        self.btype.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateBlockExt {
+    fn map_positions(&mut self, map: &PositionMap) {
+        match self {
+            DeflateBlockExt::Stored(stored) => stored.map_positions(map),
+            DeflateBlockExt::Fixed(fixed) => fixed.map_positions(map),
+            DeflateBlockExt::Dynamic(dynamic) => dynamic.map_positions(map),
+        }
+    }
+}
+
+impl MapPositions for DeflateBlockStored {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.padding.map_positions(map);
+        self.len.map_positions(map);
+        self.nlen.map_positions(map);
+        self.data.map_positions(map);
+        self.warnings.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateBlockFixed {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.tokens.map_positions(map);
+    }
+}
+
+impl MapPositions for DeflateBlockDynamic {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.dht.map_positions(map);
+        self.tokens.map_positions(map);
+    }
+}
+
+impl MapPositions for DynamicHuffmanTable {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.hlit.map_positions(map);
+        self.hdist.map_positions(map);
+        self.hclen.map_positions(map);
+        self.hclens.map_positions(map);
+        self.hclens_codes.map_positions(map);
+        self.hlits.map_positions(map);
+        self.hlits_rle.map_positions(map);
+        self.hlits_codes.map_positions(map);
+        self.hdists.map_positions(map);
+        self.hdists_codes.map_positions(map);
+    }
+}
+
+impl MapPositions for CodeLengthCode {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.symbol.map_positions(map);
+        self.repeat.map_positions(map);
+    }
+}
+
+impl<T> MapPositions for HuffmanCode<T> {
+    fn map_positions(&mut self, map: &PositionMap) {
+        self.len.map_positions(map);
+    }
+}
+
+impl MapPositions for Token {
+    fn map_positions(&mut self, map: &PositionMap) {
+        if let Token::Window(window) = self {
+            window.length.map_positions(map);
+            window.length_extra.map_positions(map);
+            window.distance.map_positions(map);
+            window.distance_extra.map_positions(map);
+        }
+    }
+}
+
+/// 11.2.2 IHDR Image header: the number of bits per pixel.
+fn bits_per_pixel(header: Option<&PngHeader>) -> Option<usize> {
+    let header = header?;
+    let bit_depth = header.bit_depth.as_ref()?.v as usize;
+    let channels = match header.color_type.as_ref()?.v {
+        0 => 1,
+        2 => 3,
+        3 => 1,
+        4 => 2,
+        6 => 4,
+        _ => return None,
+    };
+    Some(bit_depth * channels)
+}
+
+/// 8.2 Interlace methods: (pass, width, height) of each reduced image.
+fn reduced_images(width: u32, height: u32, interlace: bool) -> Vec<(u8, u32, u32)> {
+    if !interlace {
+        return vec![(0, width, height)];
+    }
+    ADAM7_PASSES
+        .iter()
+        .zip(1..)
+        .map(|((x0, y0, dx, dy), pass)| {
+            (
+                pass,
+                width.saturating_sub(*x0).div_ceil(*dx),
+                height.saturating_sub(*y0).div_ceil(*dy),
+            )
+        })
+        .filter(|(_, width, height)| *width != 0 && *height != 0)
+        .collect()
+}
+
+fn find_scanline(scanlines: &[PngScanline], plain_pos: usize) -> Option<usize> {
+    scanlines
+        .partition_point(|scanline| scanline.plain_pos <= plain_pos)
+        .checked_sub(1)
+}
+
+fn list_scanlines(
+    stream: &mut PngStream,
+    header: Option<&PngHeader>,
+    plain: &[u8],
+    matches: &[(usize, usize)],
+    pos: usize,
+) {
+    let bpp = match bits_per_pixel(header) {
+        Some(bpp) => bpp,
+        None => return,
+    };
+    let interlace = header
+        .and_then(|header| header.interlace_method.as_ref())
+        .is_some_and(|x| x.v == 1);
+    // 7.2 Scanlines: each scanline starts with a filter-type byte
+    let mut plain_pos = 0;
+    for (pass, width, height) in reduced_images(stream.width, stream.height, interlace) {
+        let stride = 1 + (width as usize * bpp).div_ceil(8);
+        for row in 0..height {
+            if plain_pos > plain.len() {
+                // A corrupt height does not get a scanline for each row it claims
+                let rows = (height - row) as usize;
+                plain_pos = plain_pos.saturating_add(stride.saturating_mul(rows));
+                break;
+            }
+            stream.scanlines.push(PngScanline {
+                pass,
+                row,
+                plain_pos,
+                filter: plain.get(plain_pos).copied(),
+                matched_scanlines: Vec::new(),
+            });
+            plain_pos += stride;
+        }
+    }
+    if plain.len() != plain_pos {
+        stream.warnings.push(ParseError {
+            kind: ErrorKind::SizeMismatch,
+            pos,
+            msg: format!("Image data size (expected={})", plain_pos),
+            context: None,
+        });
+    }
+    // Which earlier rows the encoder copied each row's matches from
+    for (plain_pos, distance) in matches {
+        if let (Some(row), Some(source)) = (
+            find_scanline(&stream.scanlines, *plain_pos),
+            find_scanline(&stream.scanlines, plain_pos - distance),
+        ) {
+            stream.scanlines[row].matched_scanlines.push(source);
+        }
+    }
+    for scanline in &mut stream.scanlines {
+        scanline.matched_scanlines.sort_unstable();
+        scanline.matched_scanlines.dedup();
+    }
+}
+
+fn parse_stream(
+    stream: &mut PngStream,
+    segments: Vec<Segment>,
+    header: Option<&PngHeader>,
+    file: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let pos = segments.first().map_or(0, |segment| segment.file * 8);
+    let mut joined = Vec::new();
+    for segment in &segments {
+        let start = segment.file - file.base;
+        joined.extend_from_slice(&file.bytes[start..start + segment.len]);
+    }
+    let map = PositionMap { segments };
+    let mut data = DataStream::from_bytes(joined, 0);
+    // The joined data is parsed on behalf of the file's visitor
+    std::mem::swap(&mut data.visitor, &mut file.visitor);
+    window.reset();
+    window.plain_pos = 0;
+    window.capture = Some(Vec::new());
+    window.matches = Some(Vec::new());
+    let mut result = parse_zlib(&mut stream.zlib, &mut data, window, settings);
+    std::mem::swap(&mut data.visitor, &mut file.visitor);
+    let plain = window.capture.take().unwrap_or_default();
+    let matches = window.matches.take().unwrap_or_default();
+    if result.is_ok() && data.pos != data.end {
+        stream.zlib.warnings.push(ParseError {
+            kind: ErrorKind::Garbage,
+            pos: data.pos,
+            msg: format!("Garbage (end={})", map.end(data.end)),
+            context: None,
+        });
+    }
+    stream.zlib.map_positions(&map);
+    if let Err(err) = &mut result {
+        err.map_positions(&map);
+    }
+    list_scanlines(stream, header, &plain, &matches, pos);
+    result
+}
+
+fn check_sequence_number(
+    sequence_number: &Value<u32>,
+    expected: &mut u32,
+    warnings: &mut Vec<ParseError>,
+) {
+    // APNG: The sequence number of the animation chunk, starting from 0
+    if sequence_number.v != *expected {
+        warnings.push(ParseError {
+            kind: ErrorKind::Inconsistent,
+            pos: sequence_number.start,
+            msg: format!("Sequence number (expected={})", expected),
+            context: None,
+        });
+    }
+    *expected = sequence_number.v.wrapping_add(1);
+}
+
+fn parse_header(header: &mut PngHeader, data: &mut DataStream) -> Result<(), Error> {
+    // 11.2.2 IHDR Image header
+    data.pop_be(&mut header.width)?;
+    data.pop_be(&mut header.height)?;
+    data.pop_be(&mut header.bit_depth)?;
+    data.pop_be(&mut header.color_type)?;
+    data.pop_be(&mut header.compression_method)?;
+    data.pop_be(&mut header.filter_method)?;
+    data.pop_be(&mut header.interlace_method)?;
+    Ok(())
+}
+
+pub(crate) fn parse_png(
+    png: &mut PngImage,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    if data.pop_hex(&mut png.signature, PNG_SIGNATURE.len())? != PNG_SIGNATURE {
+        return Err(data.parse_error(ErrorKind::BadHeader, "PNG signature"));
+    }
+    let mut segments: Vec<Vec<Segment>> = Vec::new();
+    let mut idat_stream: Option<usize> = None;
+    let mut fdat_stream: Option<usize> = None;
+    let mut sequence_number = 0;
+    // 5.3 Chunk layout
+    while data.pos != data.end {
+        png.chunks.push(PngChunk {
+            offset: data.byte_index()?,
+            length: None,
+            chunk_type: None,
+            sequence_number: None,
+            crc: None,
+            crc_computed: None,
+        });
+        let index = png.chunks.len() - 1;
+        let chunk = &mut png.chunks[index];
+        let length = data.pop_be(&mut chunk.length)?.v as usize;
+        let type_index = data.byte_index()?;
+        data.require((4 + length + 4) * 8)?;
+        let type_start = type_index - data.base;
+        let chunk_type: [u8; 4] = [
+            data.bytes[type_start],
+            data.bytes[type_start + 1],
+            data.bytes[type_start + 2],
+            data.bytes[type_start + 3],
+        ];
+        chunk.chunk_type = Some(Value {
+            v: chunk_type.iter().map(|byte| char::from(*byte)).collect(),
+            start: data.pos,
+            end: data.pos + 32,
+        });
+        data.drop(32)?;
+        let chunk_end = data.pos + length * 8;
+        match &chunk_type {
+            b"IHDR" => {
+                if length == 13 {
+                    parse_header(png.header.insert(PngHeader::default()), data)?;
+                } else {
+                    png.warnings.push(ParseError {
+                        kind: ErrorKind::BadHeader,
+                        pos: data.pos,
+                        msg: format!("IHDR length={}", length),
+                        context: None,
+                    });
+                }
+            }
+            b"IDAT" => {
+                let stream = match idat_stream {
+                    Some(stream) => stream,
+                    None => {
+                        let header = png.header.as_ref();
+                        png.streams.push(PngStream {
+                            width: header.and_then(|x| x.width.as_ref()).map_or(0, |x| x.v),
+                            height: header.and_then(|x| x.height.as_ref()).map_or(0, |x| x.v),
+                            ..PngStream::default()
+                        });
+                        segments.push(Vec::new());
+                        *idat_stream.insert(png.streams.len() - 1)
+                    }
+                };
+                png.streams[stream].chunks.push(index);
+                segments[stream].push(Segment {
+                    logical: 0,
+                    file: data.pos / 8,
+                    len: length,
+                });
+            }
+            // APNG: fcTL Frame Control Chunk
+            b"fcTL" if length >= 12 => {
+                let sequence = data.pop_be(&mut chunk.sequence_number)?;
+                check_sequence_number(sequence, &mut sequence_number, &mut png.warnings);
+                let mut width: Option<Value<u32>> = None;
+                let mut height: Option<Value<u32>> = None;
+                let width = data.pop_be(&mut width)?.v;
+                let height = data.pop_be(&mut height)?.v;
+                // The default image, if it is part of the animation, is described by the
+                // fcTL chunk that precedes IDAT
+                if idat_stream.is_some() {
+                    png.streams.push(PngStream {
+                        width,
+                        height,
+                        ..PngStream::default()
+                    });
+                    segments.push(Vec::new());
+                    fdat_stream = Some(png.streams.len() - 1);
+                }
+            }
+            // APNG: fdAT Frame Data Chunk
+            b"fdAT" if length >= 4 => {
+                let sequence = data.pop_be(&mut chunk.sequence_number)?;
+                check_sequence_number(sequence, &mut sequence_number, &mut png.warnings);
+                match fdat_stream {
+                    Some(stream) => {
+                        png.streams[stream].chunks.push(index);
+                        segments[stream].push(Segment {
+                            logical: 0,
+                            file: data.pos / 8,
+                            len: length - 4,
+                        });
+                    }
+                    None => png.warnings.push(ParseError {
+                        kind: ErrorKind::Inconsistent,
+                        pos: data.pos,
+                        msg: String::from("fdAT without fcTL"),
+                        context: None,
+                    }),
+                }
+            }
+            _ => {}
+        }
+        data.pos = chunk_end;
+        let chunk = &mut png.chunks[index];
+        let crc_computed = crc32(&data.bytes[type_start..type_start + 4 + length]);
+        chunk.crc_computed = Some(crc_computed);
+        let crc = data.pop_be(&mut chunk.crc)?;
+        if crc.v != crc_computed {
+            png.warnings.push(ParseError {
+                kind: ErrorKind::ChecksumMismatch,
+                pos: crc.start,
+                msg: format!("CRC (computed=0x{:08x})", crc_computed),
+                context: None,
+            });
+        }
+        if &chunk_type == b"IEND" {
+            break;
+        }
+    }
+    for (stream, mut segments) in png.streams.iter_mut().zip(segments) {
+        let mut logical = 0;
+        for segment in &mut segments {
+            segment.logical = logical;
+            logical += segment.len;
+        }
+        let header = png.header.as_ref();
+        parse_stream(stream, segments, header, data, window, settings)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use crate::checksum::{Adler32, Crc32};
+use crate::data::{CompressedStream, DeflateStream, GzipFile, ScanHit, ScanResult, ZlibStream};
+use crate::error::Error;
+use crate::{parse_compressed_stream, DataStream, Settings, Window};
+
+fn new_stream(format: &str) -> CompressedStream {
+    match format {
+        "gzip" => CompressedStream::Gzip(GzipFile {
+            members: Vec::new(),
+            bgzf_offsets: None,
+        }),
+        "zlib" => CompressedStream::Zlib(ZlibStream::default()),
+        _ => CompressedStream::Raw(DeflateStream::default()),
+    }
+}
+
+/// Whether the stream carries checksums, and all of them match the decoded data.
+fn checksums_match(stream: &CompressedStream) -> bool {
+    match stream {
+        CompressedStream::Gzip(gzip) => gzip.members.iter().all(|member| {
+            member.checksum.as_ref().map(|x| x.v) == member.checksum_computed
+                && member.len.as_ref().map(|x| x.v) == member.len_computed
+        }),
+        CompressedStream::Zlib(zlib) => zlib.adler32.as_ref().map(|x| x.v) == zlib.adler32_computed,
+        _ => true,
+    }
+}
+
+fn try_format(
+    format: &str,
+    data: &mut DataStream,
+    start: usize,
+    window: &mut Window,
+    settings: &Settings,
+) -> Option<CompressedStream> {
+    data.pos = start;
+    window.reset();
+    window.plain_pos = 0;
+    window.crc32 = Crc32::default();
+    window.adler32 = Adler32::default();
+    let mut stream = new_stream(format);
+    match parse_compressed_stream(&mut stream, data, window, settings) {
+        Ok(()) if checksums_match(&stream) => Some(stream),
+        _ => None,
+    }
+}
+
+fn has_header(format: &str, data: &DataStream, pos: usize) -> bool {
+    match (format, &data.bytes[pos / 8..data.end / 8]) {
+        ("gzip", [0x1f, 0x8b, ..]) => true,
+        // RFC 1950 2.2. Data format
+        ("zlib", [cmf, flg, ..]) => {
+            cmf & 0xf == 8 && (u16::from(*cmf) * 256 + u16::from(*flg)) % 31 == 0
+        }
+        _ => false,
+    }
+}
+
+fn try_hit(
+    scan: &ScanResult,
+    format: &str,
+    data: &mut DataStream,
+    pos: usize,
+    window: &mut Window,
+    settings: &Settings,
+) -> Option<ScanHit> {
+    // Trial parses do not record the data, the hits are parsed again with the given settings
+    if format != "raw" && !has_header(format, data, pos) {
+        return None;
+    }
+    let trial_settings = Settings {
+        data: false,
+        ..*settings
+    };
+    try_format(format, data, pos, window, &trial_settings)?;
+    let plain_len = window.plain_pos;
+    if format == "raw" && plain_len < scan.min_plain_len {
+        return None;
+    }
+    let stream = try_format(format, data, pos, window, settings)?;
+    Some(ScanHit {
+        offset: pos,
+        end: data.pos,
+        format: String::from(format),
+        compressed_len: (data.pos - pos).div_ceil(8),
+        plain_len,
+        stream,
+    })
+}
+
+fn scan_range(
+    scan: &ScanResult,
+    formats: &[&str],
+    data: &mut DataStream,
+    range: (usize, usize),
+    step: usize,
+    window: &mut Window,
+    settings: &Settings,
+) -> Vec<ScanHit> {
+    let end = data.end;
+    data.end = range.1;
+    let mut hits = Vec::new();
+    let mut pos = range.0.next_multiple_of(step);
+    while pos < data.end {
+        let hit = formats
+            .iter()
+            .find_map(|format| try_hit(scan, format, data, pos, window, settings));
+        match hit {
+            Some(hit) => {
+                // Do not report the streams that are nested in this one
+                pos = hit.end.next_multiple_of(step);
+                hits.push(hit);
+            }
+            None => pos += step,
+        }
+    }
+    data.end = end;
+    hits
+}
+
+pub(crate) fn scan(
+    scan: &mut ScanResult,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // Streams with checksums are much less likely to be false positives, so find them first and
+    // look for raw deflate streams only in the gaps between them
+    // A run of zero bytes parses as a chain of empty stored blocks unless their NLEN is checked
+    data.strict = true;
+    let range = (data.pos, data.end);
+    let checked = scan_range(scan, &["gzip", "zlib"], data, range, 8, window, settings);
+    let mut gaps = Vec::new();
+    let mut gap_start = range.0;
+    for hit in &checked {
+        gaps.push((gap_start, hit.offset));
+        gap_start = hit.end;
+    }
+    gaps.push((gap_start, range.1));
+    let step = if scan.bit_offsets { 1 } else { 8 };
+    scan.hits = checked;
+    for gap in gaps {
+        let hits = scan_range(scan, &["raw"], data, gap, step, window, settings);
+        scan.hits.extend(hits);
+    }
+    scan.hits.sort_by_key(|hit| hit.offset);
+    data.pos = data.end;
+    data.strict = false;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use crate::data::{
+    DeflateBlock, DynamicHuffmanTable, GzipStream, Token, Value, WebSocketMessage, ZipEntry,
+    ZlibStream,
+};
+use crate::error::Error;
+
+/// The header of the container of a deflate stream, parsed up to the start of the compressed
+/// data. The zlib streams of png, pdf and git files are reported as `Zlib`.
+pub enum ContainerHeader<'a> {
+    Gzip(&'a GzipStream),
+    Zlib(&'a ZlibStream),
+    /// The local file header of a deflated zip entry.
+    Zip(&'a ZipEntry),
+    /// A websocket message, reported before its payload.
+    WebSocket(&'a WebSocketMessage),
+}
+
+/// Callbacks that the parser makes as it decodes the data. A visitor that does not keep the
+/// blocks sees each of them only through the callbacks, and the parser drops it after
+/// `on_block_end`, so its memory use stays bounded by a single block.
+///
+/// The formats that need random access to the data are read into memory and keep their blocks
+/// regardless. The positions in png image data are relative to the joined IDAT or fdAT chunks
+/// until the model maps them to the file. Scan results are reported only through the model.
+pub trait ParseVisitor {
+    fn on_container_header(&mut self, _header: ContainerHeader) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// `index` is the index of the block in its deflate stream, only the header of `block` is
+    /// parsed at this point.
+    fn on_block_start(&mut self, _index: usize, _block: &DeflateBlock) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn on_dht(&mut self, _dht: &DynamicHuffmanTable) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn on_token(&mut self, _token: &Value<Token>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn on_block_end(&mut self, _index: usize, _block: &DeflateBlock) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the parser keeps the decoded blocks in the model.
+    fn keep_blocks(&self) -> bool {
+        false
+    }
+}
+
+/// The visitor of `parse`, `parse_bytes` and `parse_reader`. The parser always builds the model,
+/// this visitor ignores the callbacks and makes the parser keep every block in it.
+pub struct Model;
+
+impl ParseVisitor for Model {
+    fn keep_blocks(&self) -> bool {
+        true
+    }
+}
+
+impl<V: ParseVisitor + ?Sized> ParseVisitor for &mut V {
+    fn on_container_header(&mut self, header: ContainerHeader) -> Result<(), Error> {
+        (**self).on_container_header(header)
+    }
+
+    fn on_block_start(&mut self, index: usize, block: &DeflateBlock) -> Result<(), Error> {
+        (**self).on_block_start(index, block)
+    }
+
+    fn on_dht(&mut self, dht: &DynamicHuffmanTable) -> Result<(), Error> {
+        (**self).on_dht(dht)
+    }
+
+    fn on_token(&mut self, token: &Value<Token>) -> Result<(), Error> {
+        (**self).on_token(token)
+    }
+
+    fn on_block_end(&mut self, index: usize, block: &DeflateBlock) -> Result<(), Error> {
+        (**self).on_block_end(index, block)
+    }
+
+    fn keep_blocks(&self) -> bool {
+        (**self).keep_blocks()
+    }
+}
@@ -0,0 +1,143 @@
+// RFC 7692 permessage-deflate sessions. Messages come either from a capture file, where each
+// message payload is preceded by its 4-byte big-endian length, or from a directory, where each
+// file holds one message payload and the messages are ordered by file name.
+
+use std::fs;
+use std::path::Path;
+
+use crate::data::{DeflateStream, WebSocketMessage, WebSocketSession};
+use crate::error::{Error, ErrorKind, ParseError};
+use crate::visitor::ContainerHeader;
+use crate::{parse_deflate_block, DataStream, Settings, Window};
+
+// 7.2.2 Decompression: Append 4 octets of 0x00 0x00 0xff 0xff to the tail end of the payload of
+// the message
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+fn new_message(offset: usize, plain_start: usize) -> WebSocketMessage {
+    WebSocketMessage {
+        path: None,
+        length: None,
+        offset,
+        deflate: DeflateStream::default(),
+        trailer_block: None,
+        plain_start,
+        plain_end: None,
+        warnings: Vec::new(),
+    }
+}
+
+fn parse_message_blocks(
+    message: &mut WebSocketMessage,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    data.visitor
+        .on_container_header(ContainerHeader::WebSocket(message))?;
+    // Blocks that end with a sync flush are not final, so stop at the end of the message instead
+    while parse_deflate_block(&mut message.deflate.blocks, data, window, settings)?
+        && data.pos < data.end
+    {}
+    data.align(&mut message.deflate.padding)?;
+    message.plain_end = Some(window.plain_pos);
+    // A message that ends with a BFINAL=1 block leaves the appended trailer unused
+    let trailer_start = data.end - TRAILER.len() * 8;
+    if data.pos != data.end && data.pos != trailer_start {
+        message.warnings.push(ParseError {
+            kind: ErrorKind::Garbage,
+            pos: data.pos,
+            msg: format!("Garbage (end={})", trailer_start),
+            context: None,
+        });
+    }
+    Ok(())
+}
+
+/// Parses the message payload at `start..end` bytes with the trailer temporarily written over
+/// the bytes that follow it, so that the positions stay relative to the start of the file.
+fn parse_message(
+    message: &mut WebSocketMessage,
+    data: &mut DataStream,
+    start: usize,
+    end: usize,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let len = data.bytes.len();
+    let saved = data.bytes[end..(end + TRAILER.len()).min(len)].to_vec();
+    data.bytes.resize(len.max(end + TRAILER.len()), 0);
+    data.bytes[end..end + TRAILER.len()].copy_from_slice(&TRAILER);
+    let data_end = data.end;
+    data.pos = start * 8;
+    data.end = (end + TRAILER.len()) * 8;
+    let result = parse_message_blocks(message, data, window, settings);
+    message.trailer_block = message
+        .deflate
+        .blocks
+        .iter()
+        .position(|block| block.end.is_some_and(|block_end| block_end > end * 8));
+    data.bytes[end..end + saved.len()].copy_from_slice(&saved);
+    data.bytes.truncate(len);
+    data.pos = end * 8;
+    data.end = data_end;
+    result
+}
+
+fn start_message(session: &WebSocketSession, window: &mut Window) {
+    // 7.1.1.1 server_no_context_takeover / 7.1.1.2 client_no_context_takeover: the sender starts
+    // each message with an empty LZ77 sliding window
+    if session.no_context_takeover {
+        window.reset();
+    }
+}
+
+pub(crate) fn parse_websocket(
+    session: &mut WebSocketSession,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    while data.pos != data.end {
+        start_message(session, window);
+        session
+            .messages
+            .push(new_message(data.byte_index()? + 4, window.plain_pos));
+        let message = match session.messages.last_mut() {
+            Some(x) => x,
+            None => unreachable!(),
+        };
+        let length = data.pop_be(&mut message.length)?.v as usize;
+        let start = data.byte_index()?;
+        data.require(length * 8)?;
+        parse_message(message, data, start, start + length, window, settings)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_websocket_directory(
+    session: &mut WebSocketSession,
+    path: &Path,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let mut paths = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    for path in paths {
+        let mut data = DataStream::new(&path, 0)?;
+        let end = data.bytes.len();
+        start_message(session, window);
+        session.messages.push(new_message(0, window.plain_pos));
+        let message = match session.messages.last_mut() {
+            Some(x) => x,
+            None => unreachable!(),
+        };
+        message.path = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        parse_message(message, &mut data, 0, end, window, settings)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,906 @@
+use crate::checksum::Crc32;
+use crate::data::{
+    DeflateStream, Latin1String, Value, Zip64EndOfCentralDirectory,
+    Zip64EndOfCentralDirectoryLocator, Zip64ExtraField, ZipArchive, ZipCentralEntry,
+    ZipDataDescriptor, ZipEndOfCentralDirectory, ZipEntry,
+};
+use crate::error::{Error, ErrorKind, ParseError};
+use crate::visitor::ContainerHeader;
+use crate::{parse_deflate, DataStream, Settings, Window};
+
+// APPNOTE.TXT 4.3.7 Local file header
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+// APPNOTE.TXT 4.3.9.3 Data descriptor
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+// APPNOTE.TXT 4.3.12 Central directory structure
+const CENTRAL_FILE_HEADER_SIGNATURE: u32 = 0x02014b50;
+// APPNOTE.TXT 4.3.14 Zip64 end of central directory record
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+// APPNOTE.TXT 4.3.15 Zip64 end of central directory locator
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+// APPNOTE.TXT 4.3.16 End of central directory record
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+// APPNOTE.TXT 4.5.3 Zip64 Extended Information Extra Field
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+fn pop_name(
+    data: &mut DataStream,
+    out: &mut Option<Value<Latin1String>>,
+    n: usize,
+    utf8: bool,
+) -> Result<(), Error> {
+    let mut hex: Option<Value<String>> = None;
+    let bytes = data.pop_hex(&mut hex, n)?;
+    // APPNOTE.TXT 4.4.4 general purpose bit flag: Bit 11: Language encoding flag (EFS)
+    let text = if utf8 {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        bytes.iter().map(|byte| char::from(*byte)).collect()
+    };
+    *out = hex.map(|hex| Value {
+        v: Latin1String { text, hex: hex.v },
+        start: hex.start,
+        end: hex.end,
+    });
+    Ok(())
+}
+
+fn widen(value: &Value<u32>) -> Value<u64> {
+    Value {
+        v: u64::from(value.v),
+        start: value.start,
+        end: value.end,
+    }
+}
+
+fn pop_le_u32_as_u64(data: &mut DataStream, out: &mut Option<Value<u64>>) -> Result<(), Error> {
+    let mut value: Option<Value<u32>> = None;
+    data.pop_le(&mut value)?;
+    *out = value.map(|value| widen(&value));
+    Ok(())
+}
+
+/// The value of a 32-bit field, or of its ZIP64 counterpart if the field is 0xFFFFFFFF.
+fn effective(value: &Option<Value<u32>>, zip64: Option<&Value<u64>>) -> Option<Value<u64>> {
+    match (value, zip64) {
+        (Some(value), Some(zip64)) if value.v == 0xffffffff => Some(zip64.clone()),
+        (Some(value), _) => Some(widen(value)),
+        (None, _) => None,
+    }
+}
+
+/// Reads `N` bytes at a byte index without moving, if they are within the input.
+fn read_bytes<const N: usize>(data: &DataStream, index: usize) -> Option<[u8; N]> {
+    if index < data.base || index + N > data.end / 8 {
+        return None;
+    }
+    data.bytes[index - data.base..index - data.base + N]
+        .try_into()
+        .ok()
+}
+
+fn read_u16(data: &DataStream, index: usize) -> Option<u16> {
+    read_bytes(data, index).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &DataStream, index: usize) -> Option<u32> {
+    read_bytes(data, index).map(u32::from_le_bytes)
+}
+
+fn parse_zip64_extra_field(
+    data: &mut DataStream,
+    extra: &Option<Value<String>>,
+    fields: [bool; 4],
+) -> Result<Option<Zip64ExtraField>, Error> {
+    // 4.5.1 In order to allow different programs and different types of information to be
+    // stored in the 'extra' field in .ZIP files, the following structure MUST be used for all
+    // programs storing data in this field: header1+data1 + header2+data2 . . .
+    let (mut index, end) = match extra {
+        Some(extra) => (extra.start / 8, extra.end / 8),
+        None => return Ok(None),
+    };
+    while index + 4 <= end {
+        let (id, size) = match (read_u16(data, index), read_u16(data, index + 2)) {
+            (Some(id), Some(size)) => (id, size as usize),
+            _ => break,
+        };
+        if id == ZIP64_EXTRA_FIELD_ID {
+            // 4.5.3 The order of the fields in the zip64 extended information record is fixed,
+            // but the fields MUST only appear if the corresponding Local or Central directory
+            // record field is set to 0xFFFF or 0xFFFFFFFF
+            let (pos, data_end) = (data.pos, data.end);
+            data.pos = (index + 4) * 8;
+            data.end = (index + 4 + size).min(end) * 8;
+            let mut zip64 = Zip64ExtraField::default();
+            if fields[0] && data.require(64).is_ok() {
+                data.pop_le(&mut zip64.uncompressed_size)?;
+            }
+            if fields[1] && data.require(64).is_ok() {
+                data.pop_le(&mut zip64.compressed_size)?;
+            }
+            if fields[2] && data.require(64).is_ok() {
+                data.pop_le(&mut zip64.local_header_offset)?;
+            }
+            if fields[3] && data.require(32).is_ok() {
+                data.pop_le(&mut zip64.disk_start)?;
+            }
+            data.pos = pos;
+            data.end = data_end;
+            return Ok(Some(zip64));
+        }
+        index += 4 + size;
+    }
+    Ok(None)
+}
+
+fn parse_data_descriptor(
+    out: &mut ZipDataDescriptor,
+    data: &mut DataStream,
+    zip64: bool,
+) -> Result<(), Error> {
+    // Although not originally assigned a signature, the value 0x08074b50 has commonly been
+    // adopted as a signature value for the data descriptor record
+    if data.peek_le::<u32>()?.v == DATA_DESCRIPTOR_SIGNATURE {
+        data.pop_le(&mut out.signature)?;
+    }
+    data.pop_le(&mut out.crc32)?;
+    // For ZIP64 format archives, the compressed and uncompressed sizes are 8 bytes each
+    if zip64 {
+        data.pop_le(&mut out.compressed_size)?;
+        data.pop_le(&mut out.uncompressed_size)?;
+    } else {
+        pop_le_u32_as_u64(data, &mut out.compressed_size)?;
+        pop_le_u32_as_u64(data, &mut out.uncompressed_size)?;
+    }
+    Ok(())
+}
+
+fn find_stored_size(data: &DataStream) -> Result<usize, Error> {
+    // The size of a stored entry followed by a data descriptor is not known in advance: look
+    // for a signed data descriptor whose compressed size matches the distance to it
+    let start = data.byte_index()?;
+    let end = data.end / 8;
+    (start..end.saturating_sub(15))
+        .find(|index| {
+            read_u32(data, *index) == Some(DATA_DESCRIPTOR_SIGNATURE)
+                && read_u32(data, index + 8).map(|size| size as usize) == Some(index - start)
+        })
+        .map(|index| index - start)
+        .ok_or_else(|| data.parse_error(ErrorKind::Inconsistent, "Data descriptor"))
+}
+
+/// CRC-32, compressed size and uncompressed size as declared by the data descriptor or, if there
+/// is none, by the local file header.
+struct DeclaredValues {
+    crc32: Option<Value<u32>>,
+    compressed_size: Option<Value<u64>>,
+    uncompressed_size: Option<Value<u64>>,
+}
+
+fn declared_values(entry: &ZipEntry) -> DeclaredValues {
+    match &entry.data_descriptor {
+        Some(data_descriptor) => DeclaredValues {
+            crc32: data_descriptor.crc32.clone(),
+            compressed_size: data_descriptor.compressed_size.clone(),
+            uncompressed_size: data_descriptor.uncompressed_size.clone(),
+        },
+        None => {
+            let zip64 = entry.zip64.as_ref();
+            DeclaredValues {
+                crc32: entry.crc32.clone(),
+                compressed_size: effective(
+                    &entry.compressed_size,
+                    zip64.and_then(|zip64| zip64.compressed_size.as_ref()),
+                ),
+                uncompressed_size: effective(
+                    &entry.uncompressed_size,
+                    zip64.and_then(|zip64| zip64.uncompressed_size.as_ref()),
+                ),
+            }
+        }
+    }
+}
+
+fn check_entry(entry: &mut ZipEntry) {
+    let DeclaredValues {
+        crc32,
+        compressed_size,
+        uncompressed_size,
+    } = declared_values(entry);
+    if let (Some(crc32), Some(crc32_computed)) = (crc32, entry.crc32_computed) {
+        if crc32.v != crc32_computed {
+            entry.warnings.push(ParseError {
+                kind: ErrorKind::ChecksumMismatch,
+                pos: crc32.start,
+                msg: format!("CRC-32 (computed=0x{:08x})", crc32_computed),
+                context: None,
+            });
+        }
+    }
+    if let (Some(compressed_size), Some(compressed_size_computed)) =
+        (compressed_size, entry.compressed_size_computed)
+    {
+        if compressed_size.v != compressed_size_computed as u64 {
+            entry.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: compressed_size.start,
+                msg: format!("Compressed size (computed={})", compressed_size_computed),
+                context: None,
+            });
+        }
+    }
+    if let (Some(uncompressed_size), Some(uncompressed_size_computed)) =
+        (uncompressed_size, entry.uncompressed_size_computed)
+    {
+        if uncompressed_size.v != uncompressed_size_computed as u64 {
+            entry.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: uncompressed_size.start,
+                msg: format!(
+                    "Uncompressed size (computed={})",
+                    uncompressed_size_computed
+                ),
+                context: None,
+            });
+        }
+    }
+}
+
+fn parse_entry(
+    out: &mut Vec<ZipEntry>,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 4.3.7 Local file header
+    out.push(ZipEntry {
+        offset: data.byte_index()?,
+        signature: None,
+        version: None,
+        flags: None,
+        method: None,
+        time: None,
+        date: None,
+        crc32: None,
+        compressed_size: None,
+        uncompressed_size: None,
+        name_len: None,
+        extra_len: None,
+        name: None,
+        extra: None,
+        zip64: None,
+        deflate: None,
+        stored: None,
+        data_descriptor: None,
+        crc32_computed: None,
+        compressed_size_computed: None,
+        uncompressed_size_computed: None,
+        warnings: Vec::new(),
+    });
+    let entry = match out.last_mut() {
+        Some(x) => x,
+        None => unreachable!(),
+    };
+    data.pop_le(&mut entry.signature)?;
+    data.pop_le(&mut entry.version)?;
+    let flags = data.pop_le(&mut entry.flags)?.v;
+    let method = data.pop_le(&mut entry.method)?.clone();
+    data.pop_le(&mut entry.time)?;
+    data.pop_le(&mut entry.date)?;
+    data.pop_le(&mut entry.crc32)?;
+    data.pop_le(&mut entry.compressed_size)?;
+    data.pop_le(&mut entry.uncompressed_size)?;
+    let name_len = data.pop_le(&mut entry.name_len)?.v as usize;
+    let extra_len = data.pop_le(&mut entry.extra_len)?.v as usize;
+    pop_name(data, &mut entry.name, name_len, flags & 0x800 != 0)?;
+    data.pop_hex(&mut entry.extra, extra_len)?;
+    // 4.5.3 This entry in the Local header MUST include BOTH original and compressed file size
+    // fields
+    entry.zip64 = parse_zip64_extra_field(data, &entry.extra, [true, true, false, false])?;
+    let compressed_size = match effective(
+        &entry.compressed_size,
+        entry
+            .zip64
+            .as_ref()
+            .and_then(|zip64| zip64.compressed_size.as_ref()),
+    ) {
+        Some(x) => usize::try_from(x.v).map_err(|_| data.parse_error(ErrorKind::Eof, "EOF"))?,
+        None => unreachable!(),
+    };
+
+    // 4.3.8 File data
+    // Bit 3: the fields crc-32, compressed size and uncompressed size are set to zero in the
+    // local header and are written in the data descriptor immediately following the compressed
+    // data
+    let has_data_descriptor = flags & 8 != 0;
+    let data_start = data.pos;
+    window.reset();
+    window.plain_pos = 0;
+    window.crc32 = Crc32::default();
+    match method.v {
+        // 0 - The file is stored (no compression)
+        0 => {
+            let compressed_size = if has_data_descriptor && compressed_size == 0 {
+                find_stored_size(data)?
+            } else {
+                compressed_size
+            };
+            let bytes = data.pop_bytes(&mut entry.stored, compressed_size, settings)?;
+            window.append_bytes(bytes);
+        }
+        // 8 - The file is Deflated
+        // 9 - Enhanced Deflating using Deflate64(tm)
+        8 | 9 => {
+            let settings = Settings {
+                deflate64: method.v == 9,
+                ..*settings
+            };
+            data.visitor
+                .on_container_header(ContainerHeader::Zip(entry))?;
+            entry.deflate = Some(DeflateStream::default());
+            match &mut entry.deflate {
+                Some(deflate) => parse_deflate(deflate, data, window, &settings)?,
+                None => unreachable!(),
+            }
+        }
+        _ => {
+            if has_data_descriptor && compressed_size == 0 {
+                return Err(
+                    data.parse_error(ErrorKind::Unsupported, &format!("Method={}", method.v))
+                );
+            }
+            entry.warnings.push(ParseError {
+                kind: ErrorKind::Unsupported,
+                pos: method.start,
+                msg: format!("Method={}", method.v),
+                context: None,
+            });
+            data.drop(data.bits(compressed_size)?)?;
+            entry.compressed_size_computed = Some(compressed_size);
+            return Ok(());
+        }
+    }
+    entry.crc32_computed = Some(window.crc32.value());
+    entry.compressed_size_computed = Some((data.pos - data_start) / 8);
+    entry.uncompressed_size_computed = Some(window.plain_pos);
+
+    // 4.3.9 Data descriptor
+    if has_data_descriptor {
+        entry.data_descriptor = Some(ZipDataDescriptor {
+            signature: None,
+            crc32: None,
+            compressed_size: None,
+            uncompressed_size: None,
+        });
+        let zip64 = entry.zip64.is_some();
+        match &mut entry.data_descriptor {
+            Some(data_descriptor) => parse_data_descriptor(data_descriptor, data, zip64)?,
+            None => unreachable!(),
+        }
+    }
+    check_entry(entry);
+    Ok(())
+}
+
+fn parse_central_entry(out: &mut Vec<ZipCentralEntry>, data: &mut DataStream) -> Result<(), Error> {
+    // 4.3.12 Central directory structure: File header
+    out.push(ZipCentralEntry {
+        offset: data.byte_index()?,
+        ..Default::default()
+    });
+    let entry = match out.last_mut() {
+        Some(x) => x,
+        None => unreachable!(),
+    };
+    data.pop_le(&mut entry.signature)?;
+    data.pop_le(&mut entry.version_made_by)?;
+    data.pop_le(&mut entry.version)?;
+    let flags = data.pop_le(&mut entry.flags)?.v;
+    data.pop_le(&mut entry.method)?;
+    data.pop_le(&mut entry.time)?;
+    data.pop_le(&mut entry.date)?;
+    data.pop_le(&mut entry.crc32)?;
+    let compressed_size = data.pop_le(&mut entry.compressed_size)?.v;
+    let uncompressed_size = data.pop_le(&mut entry.uncompressed_size)?.v;
+    let name_len = data.pop_le(&mut entry.name_len)?.v as usize;
+    let extra_len = data.pop_le(&mut entry.extra_len)?.v as usize;
+    let comment_len = data.pop_le(&mut entry.comment_len)?.v as usize;
+    let disk_start = data.pop_le(&mut entry.disk_start)?.v;
+    data.pop_le(&mut entry.internal_attributes)?;
+    data.pop_le(&mut entry.external_attributes)?;
+    let local_header_offset = data.pop_le(&mut entry.local_header_offset)?.v;
+    pop_name(data, &mut entry.name, name_len, flags & 0x800 != 0)?;
+    data.pop_hex(&mut entry.extra, extra_len)?;
+    pop_name(data, &mut entry.comment, comment_len, flags & 0x800 != 0)?;
+    entry.zip64 = parse_zip64_extra_field(
+        data,
+        &entry.extra,
+        [
+            uncompressed_size == 0xffffffff,
+            compressed_size == 0xffffffff,
+            local_header_offset == 0xffffffff,
+            disk_start == 0xffff,
+        ],
+    )?;
+    Ok(())
+}
+
+fn parse_zip64_end_of_central_directory(
+    out: &mut Zip64EndOfCentralDirectory,
+    data: &mut DataStream,
+) -> Result<(), Error> {
+    // 4.3.14 Zip64 end of central directory record
+    out.offset = data.byte_index()?;
+    data.pop_le(&mut out.signature)?;
+    // The value stored into the "size of zip64 end of central directory record" SHOULD be the
+    // size of the remaining record and SHOULD NOT include the leading 12 bytes
+    let size = data.pop_le(&mut out.size)?.v as usize;
+    if size < 44 {
+        return Err(data.parse_error(
+            ErrorKind::BadHeader,
+            "Zip64 end of central directory record size",
+        ));
+    }
+    data.pop_le(&mut out.version_made_by)?;
+    data.pop_le(&mut out.version)?;
+    data.pop_le(&mut out.disk)?;
+    data.pop_le(&mut out.central_directory_disk)?;
+    data.pop_le(&mut out.disk_entries)?;
+    data.pop_le(&mut out.entries)?;
+    data.pop_le(&mut out.central_directory_size)?;
+    data.pop_le(&mut out.central_directory_offset)?;
+    data.pop_hex(&mut out.extensible_data, size - 44)?;
+    Ok(())
+}
+
+fn parse_zip64_end_of_central_directory_locator(
+    out: &mut Zip64EndOfCentralDirectoryLocator,
+    data: &mut DataStream,
+) -> Result<(), Error> {
+    // 4.3.15 Zip64 end of central directory locator
+    out.offset = data.byte_index()?;
+    data.pop_le(&mut out.signature)?;
+    data.pop_le(&mut out.zip64_end_of_central_directory_disk)?;
+    data.pop_le(&mut out.zip64_end_of_central_directory_offset)?;
+    data.pop_le(&mut out.disks)?;
+    Ok(())
+}
+
+fn parse_end_of_central_directory(
+    out: &mut ZipEndOfCentralDirectory,
+    data: &mut DataStream,
+) -> Result<(), Error> {
+    // 4.3.16 End of central directory record
+    out.offset = data.byte_index()?;
+    data.pop_le(&mut out.signature)?;
+    data.pop_le(&mut out.disk)?;
+    data.pop_le(&mut out.central_directory_disk)?;
+    data.pop_le(&mut out.disk_entries)?;
+    data.pop_le(&mut out.entries)?;
+    data.pop_le(&mut out.central_directory_size)?;
+    data.pop_le(&mut out.central_directory_offset)?;
+    let comment_len = data.pop_le(&mut out.comment_len)?.v as usize;
+    pop_name(data, &mut out.comment, comment_len, false)?;
+    Ok(())
+}
+
+fn local_header_offset(entry: &ZipCentralEntry) -> Option<Value<u64>> {
+    effective(
+        &entry.local_header_offset,
+        entry
+            .zip64
+            .as_ref()
+            .and_then(|zip64| zip64.local_header_offset.as_ref()),
+    )
+}
+
+fn check_central_entry(entry: &mut ZipCentralEntry, entries: &[ZipEntry], prefix: usize) {
+    let zip64 = entry.zip64.as_ref();
+    let local_header_offset = match local_header_offset(entry) {
+        Some(x) => x,
+        None => return,
+    };
+    let local = match entries.iter().position(|local| {
+        Some(local.offset as u64) == local_header_offset.v.checked_add(prefix as u64)
+    }) {
+        Some(index) => {
+            entry.local_entry = Some(index);
+            &entries[index]
+        }
+        None => {
+            entry.warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: local_header_offset.start,
+                msg: format!("No local file header at {}", local_header_offset.v),
+                context: None,
+            });
+            return;
+        }
+    };
+    let mut warnings = Vec::new();
+    if let (Some(name), Some(local_name)) = (&entry.name, &local.name) {
+        if name.v.hex != local_name.v.hex {
+            warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: name.start,
+                msg: format!("Name (local={:?})", local_name.v.text),
+                context: None,
+            });
+        }
+    }
+    if let (Some(method), Some(local_method)) = (&entry.method, &local.method) {
+        if method.v != local_method.v {
+            warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: method.start,
+                msg: format!("Method (local={})", local_method.v),
+                context: None,
+            });
+        }
+    }
+    let DeclaredValues {
+        crc32: local_crc32,
+        compressed_size: local_compressed_size,
+        uncompressed_size: local_uncompressed_size,
+    } = declared_values(local);
+    if let (Some(crc32), Some(local_crc32)) = (&entry.crc32, local_crc32) {
+        if crc32.v != local_crc32.v {
+            warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: crc32.start,
+                msg: format!("CRC-32 (local=0x{:08x})", local_crc32.v),
+                context: None,
+            });
+        }
+    }
+    let compressed_size = effective(
+        &entry.compressed_size,
+        zip64.and_then(|zip64| zip64.compressed_size.as_ref()),
+    );
+    if let (Some(compressed_size), Some(local_compressed_size)) =
+        (compressed_size, local_compressed_size)
+    {
+        if compressed_size.v != local_compressed_size.v {
+            warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: compressed_size.start,
+                msg: format!("Compressed size (local={})", local_compressed_size.v),
+                context: None,
+            });
+        }
+    }
+    let uncompressed_size = effective(
+        &entry.uncompressed_size,
+        zip64.and_then(|zip64| zip64.uncompressed_size.as_ref()),
+    );
+    if let (Some(uncompressed_size), Some(local_uncompressed_size)) =
+        (uncompressed_size, local_uncompressed_size)
+    {
+        if uncompressed_size.v != local_uncompressed_size.v {
+            warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: uncompressed_size.start,
+                msg: format!("Uncompressed size (local={})", local_uncompressed_size.v),
+                context: None,
+            });
+        }
+    }
+    entry.warnings.append(&mut warnings);
+}
+
+fn check_end_of_central_directory(zip: &mut ZipArchive, prefix: usize) {
+    let eocd = match &zip.end_of_central_directory {
+        Some(x) => x,
+        None => return,
+    };
+    let zip64 = zip.zip64_end_of_central_directory.as_ref();
+    // The central directory ends where the next record starts
+    let central_directory_end = zip64.map(|zip64| zip64.offset).unwrap_or(eocd.offset);
+    let central_directory_start = zip
+        .central_directory
+        .first()
+        .map(|entry| entry.offset)
+        .unwrap_or(central_directory_end);
+
+    // 4.4.19 - 4.4.24: 0xFFFF and 0xFFFFFFFF mean that the value is in the ZIP64 record
+    let entries = match (
+        &eocd.entries,
+        zip64.and_then(|zip64| zip64.entries.as_ref()),
+    ) {
+        (Some(entries), Some(zip64_entries)) if entries.v == 0xffff => Some(zip64_entries.clone()),
+        (Some(entries), _) => Some(Value {
+            v: u64::from(entries.v),
+            start: entries.start,
+            end: entries.end,
+        }),
+        (None, _) => None,
+    };
+    if let Some(entries) = entries {
+        if entries.v != zip.central_directory.len() as u64 {
+            zip.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: entries.start,
+                msg: format!("Total entries (computed={})", zip.central_directory.len()),
+                context: None,
+            });
+        }
+    }
+    let central_directory_size = effective(
+        &eocd.central_directory_size,
+        zip64.and_then(|zip64| zip64.central_directory_size.as_ref()),
+    );
+    if let Some(central_directory_size) = central_directory_size {
+        let computed = central_directory_end - central_directory_start;
+        if central_directory_size.v != computed as u64 {
+            zip.warnings.push(ParseError {
+                kind: ErrorKind::SizeMismatch,
+                pos: central_directory_size.start,
+                msg: format!("Central directory size (computed={})", computed),
+                context: None,
+            });
+        }
+    }
+    let central_directory_offset = effective(
+        &eocd.central_directory_offset,
+        zip64.and_then(|zip64| zip64.central_directory_offset.as_ref()),
+    );
+    if let Some(central_directory_offset) = central_directory_offset {
+        if central_directory_offset.v.checked_add(prefix as u64)
+            != Some(central_directory_start as u64)
+        {
+            zip.warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: central_directory_offset.start,
+                msg: format!(
+                    "Central directory offset (computed={})",
+                    central_directory_start.saturating_sub(prefix)
+                ),
+                context: None,
+            });
+        }
+    }
+    let locator_offset = zip
+        .zip64_end_of_central_directory_locator
+        .as_ref()
+        .and_then(|locator| locator.zip64_end_of_central_directory_offset.as_ref());
+    match (locator_offset, zip64) {
+        (Some(locator_offset), Some(zip64))
+            if locator_offset.v.checked_add(prefix as u64) != Some(zip64.offset as u64) =>
+        {
+            zip.warnings.push(ParseError {
+                kind: ErrorKind::Inconsistent,
+                pos: locator_offset.start,
+                msg: format!(
+                    "Zip64 end of central directory offset (computed={})",
+                    zip64.offset.saturating_sub(prefix)
+                ),
+                context: None,
+            })
+        }
+        (Some(locator_offset), None) => zip.warnings.push(ParseError {
+            kind: ErrorKind::Inconsistent,
+            pos: locator_offset.start,
+            msg: String::from("No zip64 end of central directory record"),
+            context: None,
+        }),
+        _ => {}
+    }
+}
+
+/// Finds the end of central directory record, preferring the one whose comment ends the input.
+fn find_end_of_central_directory(data: &DataStream, start: usize) -> Option<usize> {
+    // 4.3.16 The record is 22 bytes long, followed by a comment of up to 65535 bytes
+    let end = data.end / 8;
+    let last = end.checked_sub(22)?;
+    let first = end.saturating_sub(22 + 0xffff).max(start);
+    let comment_end = |index: usize| match (read_u32(data, index), read_u16(data, index + 20)) {
+        (Some(END_OF_CENTRAL_DIRECTORY_SIGNATURE), Some(comment_len)) => {
+            Some(index + 22 + comment_len as usize)
+        }
+        _ => None,
+    };
+    (first..=last)
+        .rev()
+        .find(|index| comment_end(*index) == Some(end))
+        .or_else(|| {
+            (first..=last)
+                .rev()
+                .find(|index| comment_end(*index).is_some_and(|comment_end| comment_end <= end))
+        })
+}
+
+/// Finds the length of the data prepended to the archive, e.g. by a self-extractor, which the
+/// declared offsets do not account for.
+fn find_prefix(zip: &ZipArchive, data: &DataStream, start: usize, eocd_index: usize) -> usize {
+    let eocd = match &zip.end_of_central_directory {
+        Some(x) => x,
+        None => unreachable!(),
+    };
+    let zip64 = zip.zip64_end_of_central_directory.as_ref();
+    let central_directory_end = zip64.map(|zip64| zip64.offset).unwrap_or(eocd_index);
+    let is_central_directory = |index: usize| {
+        index >= start
+            && (index == central_directory_end
+                || read_u32(data, index) == Some(CENTRAL_FILE_HEADER_SIGNATURE))
+    };
+    let offset = effective(
+        &eocd.central_directory_offset,
+        zip64.and_then(|zip64| zip64.central_directory_offset.as_ref()),
+    )
+    .and_then(|offset| usize::try_from(offset.v).ok());
+    let size = effective(
+        &eocd.central_directory_size,
+        zip64.and_then(|zip64| zip64.central_directory_size.as_ref()),
+    )
+    .and_then(|size| usize::try_from(size.v).ok());
+    let offset = match offset {
+        Some(offset) if !is_central_directory(offset) => offset,
+        _ => return 0,
+    };
+    // The central directory immediately precedes the end of central directory records
+    match size.and_then(|size| central_directory_end.checked_sub(size)) {
+        Some(index) if index > offset && is_central_directory(index) => index - offset,
+        _ => 0,
+    }
+}
+
+fn parse_from_end_of_central_directory(
+    zip: &mut ZipArchive,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+    start: usize,
+    eocd_index: usize,
+) -> Result<usize, Error> {
+    data.pos = eocd_index * 8;
+    let eocd = zip
+        .end_of_central_directory
+        .insert(ZipEndOfCentralDirectory::default());
+    parse_end_of_central_directory(eocd, data)?;
+    let end = data.pos;
+
+    // 4.3.15 The locator immediately precedes the end of central directory record
+    let locator_index = eocd_index
+        .checked_sub(20)
+        .filter(|index| *index >= start)
+        .filter(|index| {
+            read_u32(data, *index) == Some(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE)
+        });
+    if let Some(locator_index) = locator_index {
+        data.pos = locator_index * 8;
+        let locator = zip
+            .zip64_end_of_central_directory_locator
+            .insert(Zip64EndOfCentralDirectoryLocator::default());
+        parse_zip64_end_of_central_directory_locator(locator, data)?;
+        // The declared offset does not account for prepended data, so also try the record
+        // without extensible data that immediately precedes the locator
+        let declared = locator
+            .zip64_end_of_central_directory_offset
+            .as_ref()
+            .and_then(|offset| usize::try_from(offset.v).ok());
+        let zip64_index = [declared, locator_index.checked_sub(56)]
+            .into_iter()
+            .flatten()
+            .find(|index| {
+                *index >= start
+                    && read_u32(data, *index) == Some(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+            });
+        if let Some(zip64_index) = zip64_index {
+            data.pos = zip64_index * 8;
+            let zip64 = zip
+                .zip64_end_of_central_directory
+                .insert(Zip64EndOfCentralDirectory::default());
+            parse_zip64_end_of_central_directory(zip64, data)?;
+        }
+    }
+
+    // 4.3.12 Central directory structure
+    let prefix = find_prefix(zip, data, start, eocd_index);
+    let central_directory_end = match &zip.zip64_end_of_central_directory {
+        Some(zip64) => zip64.offset,
+        None => eocd_index,
+    };
+    let central_directory_offset = match &zip.end_of_central_directory {
+        Some(eocd) => effective(
+            &eocd.central_directory_offset,
+            zip.zip64_end_of_central_directory
+                .as_ref()
+                .and_then(|zip64| zip64.central_directory_offset.as_ref()),
+        ),
+        None => unreachable!(),
+    };
+    let central_directory_start = central_directory_offset
+        .and_then(|offset| usize::try_from(offset.v).ok())
+        .and_then(|offset| offset.checked_add(prefix))
+        .filter(|index| *index >= start && *index <= central_directory_end);
+    if let Some(central_directory_start) = central_directory_start {
+        data.pos = central_directory_start * 8;
+        while data.pos < central_directory_end * 8
+            && read_u32(data, data.pos / 8) == Some(CENTRAL_FILE_HEADER_SIGNATURE)
+        {
+            parse_central_entry(&mut zip.central_directory, data)?;
+        }
+    }
+
+    // 4.3.7 Local file header
+    for index in 0..zip.central_directory.len() {
+        let local_index = local_header_offset(&zip.central_directory[index])
+            .and_then(|offset| usize::try_from(offset.v).ok())
+            .and_then(|offset| offset.checked_add(prefix))
+            .filter(|index| *index >= start)
+            .filter(|index| read_u32(data, *index) == Some(LOCAL_FILE_HEADER_SIGNATURE))
+            .filter(|index| zip.entries.iter().all(|entry| entry.offset != *index));
+        if let Some(local_index) = local_index {
+            data.pos = local_index * 8;
+            parse_entry(&mut zip.entries, data, window, settings)?;
+        }
+    }
+    data.pos = end;
+    Ok(prefix)
+}
+
+fn parse_in_sequence(
+    zip: &mut ZipArchive,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    loop {
+        match data.peek_le::<u32>().map(|signature| signature.v) {
+            Ok(LOCAL_FILE_HEADER_SIGNATURE) => {
+                parse_entry(&mut zip.entries, data, window, settings)?
+            }
+            Ok(CENTRAL_FILE_HEADER_SIGNATURE) => {
+                parse_central_entry(&mut zip.central_directory, data)?
+            }
+            Ok(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE) => {
+                let zip64 = zip
+                    .zip64_end_of_central_directory
+                    .insert(Zip64EndOfCentralDirectory::default());
+                parse_zip64_end_of_central_directory(zip64, data)?
+            }
+            Ok(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE) => {
+                let locator = zip
+                    .zip64_end_of_central_directory_locator
+                    .insert(Zip64EndOfCentralDirectoryLocator::default());
+                parse_zip64_end_of_central_directory_locator(locator, data)?
+            }
+            Ok(END_OF_CENTRAL_DIRECTORY_SIGNATURE) => {
+                let eocd = zip
+                    .end_of_central_directory
+                    .insert(ZipEndOfCentralDirectory::default());
+                parse_end_of_central_directory(eocd, data)?;
+                break;
+            }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_zip(
+    zip: &mut ZipArchive,
+    data: &mut DataStream,
+    window: &mut Window,
+    settings: &Settings,
+) -> Result<(), Error> {
+    // 4.3.6 Overall .ZIP file format
+    // The entries are located through the end of central directory record and the central
+    // directory, so that prepended data is skipped. Without the record, e.g. in a truncated
+    // archive, the records are parsed in sequence.
+    let start = data.byte_index()?;
+    let prefix = match find_end_of_central_directory(data, start) {
+        Some(eocd_index) => {
+            parse_from_end_of_central_directory(zip, data, window, settings, start, eocd_index)?
+        }
+        None => {
+            parse_in_sequence(zip, data, window, settings)?;
+            0
+        }
+    };
+    for entry in &mut zip.central_directory {
+        check_central_entry(entry, &zip.entries, prefix);
+    }
+    check_end_of_central_directory(zip, prefix);
+    Ok(())
+}
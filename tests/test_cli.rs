@@ -0,0 +1,71 @@
+extern crate serde_json;
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::process::{Command, Output, Stdio};
+    use std::str;
+
+    fn path(name: &str) -> PathBuf {
+        let mut result = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        result.push("tests");
+        result.push(name);
+        result
+    }
+
+    fn read(name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        File::open(path(name))
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .expect("read");
+        bytes
+    }
+
+    /// Runs the parser on `input` from stdin.
+    fn run_stdin(args: &[&str], input: &[u8]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_deflate-parser"))
+            .args(args)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("spawn");
+        child
+            .stdin
+            .take()
+            .expect("stdin")
+            .write_all(input)
+            .expect("write_all");
+        child.wait_with_output().expect("wait_with_output")
+    }
+
+    #[test]
+    fn stdin() {
+        let output = run_stdin(&["--data"], &read("hello.gz"));
+        assert!(output.status.success());
+        assert_eq!(
+            str::from_utf8(&read("hello.json")).expect("from_utf8"),
+            str::from_utf8(&output.stdout).expect("from_utf8")
+        );
+    }
+
+    #[test]
+    fn error() {
+        let output = run_stdin(&[], &read("hello.gz")[..20]);
+        assert_eq!(Some(1), output.status.code());
+        assert_eq!(
+            "error: EOF at bit 144 (byte 18, bit 0)\n",
+            str::from_utf8(&output.stderr).expect("from_utf8")
+        );
+        // The JSON has what was parsed up to the error, and the error
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).expect("from_slice");
+        assert_eq!("gzip", json["format"]);
+        assert_eq!(
+            serde_json::json!({"kind": "eof", "pos": 144, "msg": "EOF", "context": null}),
+            json["error"]
+        );
+    }
+}
@@ -4,12 +4,21 @@ extern crate deflate_parser;
 mod test {
     use std::fs::File;
     use std::io::{Read, Seek};
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use std::{io, str};
 
-    use deflate_parser::data::{CompressedStream, ZlibStream};
-    use deflate_parser::error::Error;
-    use deflate_parser::{parse, write_data, Settings, Window};
+    use deflate_parser::assembler::assemble;
+    use deflate_parser::data::{
+        CompressedStream, DeflateBlock, DeflateBlockExt, DeflateStream, Latin1String, PdfDocument,
+        ScanResult, Token, Value, WebSocketSession, ZlibStream,
+    };
+    use deflate_parser::encoder::encode_deflate;
+    use deflate_parser::error::{Error, ErrorKind};
+    use deflate_parser::visitor::{ContainerHeader, ParseVisitor};
+    use deflate_parser::{
+        get_deflate_streams, parse, parse_bytes, parse_reader, parse_streaming, write_data,
+        Settings, Window,
+    };
 
     fn path(name: &str) -> PathBuf {
         let mut result = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -28,30 +37,42 @@ mod test {
             str::from_utf8(&actual_json).expect("from_utf8")
         );
 
+        // The saved model loads back into the same model
+        let loaded: Option<CompressedStream> = serde_json::from_slice(&expected_json)?;
+        let mut loaded_json = Vec::new();
+        serde_json::to_writer_pretty(&mut loaded_json, &loaded).expect("to_writer_pretty");
+        assert_eq!(expected_json, loaded_json);
+
         let mut expected_data = Vec::new();
         File::open(path(name))?.read_to_end(&mut expected_data)?;
-        let mut actual_data = Vec::new();
-        {
-            let mut extract: File = tempfile::tempfile()?;
-            write_data(&mut extract, stream)?;
-            extract.seek(io::SeekFrom::Start(0))?;
-            extract.read_to_end(&mut actual_data)?;
+        for stream in [stream, &loaded] {
+            let mut actual_data = Vec::new();
+            {
+                let mut extract: File = tempfile::tempfile()?;
+                write_data(&mut extract, stream)?;
+                extract.seek(io::SeekFrom::Start(0))?;
+                extract.read_to_end(&mut actual_data)?;
+            }
+            assert_eq!(expected_data, actual_data);
         }
-        assert_eq!(expected_data, actual_data);
 
         Ok(())
     }
 
-    fn test_gz(name: &str) -> Result<(), Error> {
-        let mut stream: Option<CompressedStream> = None;
+    fn test_file(
+        name: &str,
+        extension: &str,
+        mut stream: Option<CompressedStream>,
+    ) -> Result<(), Error> {
         let mut window = Window::default();
         parse(
             &mut stream,
-            &path(&(name.to_owned() + ".gz")),
+            &path(&(name.to_owned() + extension)),
             &mut window,
             &Settings {
                 bit_offset: 0,
                 data: true,
+                deflate64: false,
             },
         )?;
 
@@ -59,6 +80,10 @@ mod test {
         Ok(())
     }
 
+    fn test_gz(name: &str) -> Result<(), Error> {
+        test_file(name, ".gz", None)
+    }
+
     fn test_zlib(name: &str) -> Result<(), Error> {
         let mut stream: Option<CompressedStream> =
             Some(CompressedStream::Zlib(ZlibStream::default()));
@@ -71,6 +96,7 @@ mod test {
             &Settings {
                 bit_offset: 0,
                 data: true,
+                deflate64: false,
             },
         )?;
 
@@ -83,6 +109,79 @@ mod test {
         test_gz("hello")
     }
 
+    #[test]
+    fn hello_bytes() -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        File::open(path("hello.gz"))?.read_to_end(&mut bytes)?;
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        let mut stream: Option<CompressedStream> = None;
+        parse_bytes(&mut stream, &bytes, &mut Window::default(), &settings)?;
+        test_common("hello", &stream)?;
+
+        let mut stream: Option<CompressedStream> = None;
+        parse_reader(
+            &mut stream,
+            &mut bytes.as_slice(),
+            &mut Window::default(),
+            &settings,
+        )?;
+        test_common("hello", &stream)?;
+        Ok(())
+    }
+
+    fn gzip_warnings(bytes: &[u8]) -> Result<Vec<(ErrorKind, String)>, Error> {
+        let mut stream: Option<CompressedStream> = None;
+        parse_bytes(
+            &mut stream,
+            bytes,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+        let gzip = match &stream {
+            Some(CompressedStream::Detected(detected)) => match &detected.stream {
+                CompressedStream::Gzip(gzip) => gzip,
+                _ => panic!("gzip"),
+            },
+            _ => panic!("detected"),
+        };
+        Ok(gzip.members[0]
+            .warnings
+            .iter()
+            .map(|warning| (warning.kind, warning.msg.clone()))
+            .collect())
+    }
+
+    #[test]
+    fn hello_corrupt_trailer() -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        File::open(path("hello.gz"))?.read_to_end(&mut bytes)?;
+        assert!(gzip_warnings(&bytes)?.is_empty());
+
+        // CRC32 and ISIZE are the last 8 bytes
+        let len = bytes.len();
+        bytes[len - 8] ^= 1;
+        bytes[len - 4] ^= 1;
+        assert_eq!(
+            vec![
+                (
+                    ErrorKind::ChecksumMismatch,
+                    String::from("CRC32 (computed=0x363a3020)")
+                ),
+                (ErrorKind::SizeMismatch, String::from("ISIZE (computed=6)")),
+            ],
+            gzip_warnings(&bytes)?
+        );
+        Ok(())
+    }
+
     #[test]
     fn aaa() -> Result<(), Error> {
         test_gz("aaa")
@@ -93,8 +192,1217 @@ mod test {
         test_gz("stored")
     }
 
+    #[test]
+    fn header() -> Result<(), Error> {
+        test_gz("header")
+    }
+
+    #[test]
+    fn latin1() -> Result<(), Error> {
+        test_gz("latin1")?;
+        // The bytes are not valid UTF-8, each of them is a character
+        let mut stream: Option<CompressedStream> = None;
+        parse(
+            &mut stream,
+            &path("latin1.gz"),
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+        let member = match &stream {
+            Some(CompressedStream::Detected(detected)) => match &detected.stream {
+                CompressedStream::Gzip(gzip) => &gzip.members[0],
+                _ => panic!("gzip"),
+            },
+            _ => panic!("detected"),
+        };
+        let text = |field: &Option<Value<Latin1String>>| {
+            field
+                .as_ref()
+                .map(|field| (field.v.text.clone(), field.v.hex.clone()))
+        };
+        assert_eq!(
+            Some((
+                String::from("caf\u{e9}\u{ff}.txt"),
+                String::from("636166e9ff2e747874")
+            )),
+            text(&member.name)
+        );
+        assert_eq!(
+            Some((
+                String::from("\u{a9} 2026 \u{80}"),
+                String::from("a920323032362080")
+            )),
+            text(&member.comment)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn multi() -> Result<(), Error> {
+        test_gz("multi")
+    }
+
+    #[test]
+    fn bgzf() -> Result<(), Error> {
+        test_gz("bgzf")
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        headers: Vec<&'static str>,
+        started: Vec<usize>,
+        tokens: usize,
+        ended: Vec<usize>,
+        blocks: Vec<String>,
+    }
+
+    impl ParseVisitor for Recorder {
+        fn on_container_header(&mut self, header: ContainerHeader) -> Result<(), Error> {
+            self.headers.push(match header {
+                ContainerHeader::Gzip(_) => "gzip",
+                ContainerHeader::Zlib(_) => "zlib",
+                ContainerHeader::Zip(_) => "zip",
+                ContainerHeader::WebSocket(_) => "websocket",
+            });
+            Ok(())
+        }
+
+        fn on_block_start(&mut self, index: usize, _block: &DeflateBlock) -> Result<(), Error> {
+            self.started.push(index);
+            Ok(())
+        }
+
+        fn on_token(&mut self, _token: &Value<Token>) -> Result<(), Error> {
+            self.tokens += 1;
+            Ok(())
+        }
+
+        fn on_block_end(&mut self, index: usize, block: &DeflateBlock) -> Result<(), Error> {
+            self.ended.push(index);
+            self.blocks
+                .push(serde_json::to_string(block).expect("to_string"));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bgzf_streaming() -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        let mut stream: Option<CompressedStream> = None;
+        parse(
+            &mut stream,
+            &path("bgzf.gz"),
+            &mut Window::default(),
+            &settings,
+        )?;
+        let expected = match &stream {
+            Some(stream) => get_deflate_streams(stream)
+                .into_iter()
+                .flat_map(|deflate| deflate.blocks.iter())
+                .map(|block| serde_json::to_string(block).expect("to_string"))
+                .collect::<Vec<String>>(),
+            None => panic!("stream"),
+        };
+
+        let expected_tokens = match &stream {
+            Some(stream) => get_deflate_streams(stream)
+                .into_iter()
+                .flat_map(|deflate| deflate.blocks.iter())
+                .map(|block| match &block.ext {
+                    Some(DeflateBlockExt::Fixed(fixed)) => {
+                        fixed.tokens.as_ref().map_or(0, Vec::len)
+                    }
+                    Some(DeflateBlockExt::Dynamic(dynamic)) => {
+                        dynamic.tokens.as_ref().map_or(0, Vec::len)
+                    }
+                    _ => 0,
+                })
+                .sum::<usize>(),
+            None => panic!("stream"),
+        };
+
+        let mut recorder = Recorder::default();
+        let mut stream: Option<CompressedStream> = None;
+        parse_streaming(
+            &mut stream,
+            File::open(path("bgzf.gz"))?,
+            &mut Window::default(),
+            &settings,
+            &mut recorder,
+        )?;
+        assert_eq!(expected, recorder.blocks);
+        assert_eq!(vec!["gzip"; 3], recorder.headers);
+        assert_eq!(expected_tokens, recorder.tokens);
+        assert_eq!(recorder.started, recorder.ended);
+        // The blocks are not kept, the rest of the model is
+        let streamed = match &stream {
+            Some(stream) => get_deflate_streams(stream),
+            None => panic!("stream"),
+        };
+        assert_eq!(3, streamed.len());
+        assert!(streamed.iter().all(|deflate| deflate.blocks.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn archive_streaming() -> Result<(), Error> {
+        let mut recorder = Recorder::default();
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Zip(Box::default()));
+        parse_streaming(
+            &mut stream,
+            File::open(path("archive.zip"))?,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: true,
+                deflate64: false,
+            },
+            &mut recorder,
+        )?;
+        // The archive is read into memory, so the blocks are kept as well as reported
+        let zip = match &stream {
+            Some(CompressedStream::Zip(zip)) => zip,
+            _ => panic!("zip"),
+        };
+        let deflated = zip
+            .entries
+            .iter()
+            .filter_map(|entry| entry.deflate.as_ref())
+            .collect::<Vec<&DeflateStream>>();
+        assert!(!deflated.is_empty());
+        assert_eq!(vec!["zip"; deflated.len()], recorder.headers);
+        let expected = deflated
+            .iter()
+            .flat_map(|deflate| deflate.blocks.iter())
+            .map(|block| serde_json::to_string(block).expect("to_string"))
+            .collect::<Vec<String>>();
+        assert_eq!(expected, recorder.blocks);
+        assert_eq!(recorder.started, recorder.ended);
+        Ok(())
+    }
+
+    #[test]
+    fn archive() -> Result<(), Error> {
+        test_file(
+            "archive",
+            ".zip",
+            Some(CompressedStream::Zip(Box::default())),
+        )
+    }
+
+    #[test]
+    fn zip64() -> Result<(), Error> {
+        test_file("zip64", ".zip", Some(CompressedStream::Zip(Box::default())))
+    }
+
+    #[test]
+    fn zip64_huge_size() -> Result<(), Error> {
+        let mut original = Vec::new();
+        File::open(path("zip64.zip"))?.read_to_end(&mut original)?;
+        // Stored and unsupported entries are skipped by their compressed size
+        for method in [0u16, 99] {
+            let mut bytes = original.clone();
+            bytes[8..10].copy_from_slice(&method.to_le_bytes());
+            // The compressed size in the ZIP64 extra field of the first local file header
+            bytes[47..55].copy_from_slice(&u64::MAX.to_le_bytes());
+            let mut stream: Option<CompressedStream> = Some(CompressedStream::Zip(Box::default()));
+            let result = parse_bytes(
+                &mut stream,
+                &bytes,
+                &mut Window::default(),
+                &Settings {
+                    bit_offset: 0,
+                    data: true,
+                    deflate64: false,
+                },
+            );
+            assert_eq!(Some(ErrorKind::Eof), result.err().map(|err| err.kind()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apng() -> Result<(), Error> {
+        test_file("apng", ".png", Some(CompressedStream::Png(Box::default())))
+    }
+
+    fn matched_scanlines(data: bool) -> Result<Vec<Vec<usize>>, Error> {
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Png(Box::default()));
+        parse(
+            &mut stream,
+            &path("apng.png"),
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data,
+                deflate64: false,
+            },
+        )?;
+        match &stream {
+            Some(CompressedStream::Png(png)) => Ok(png
+                .streams
+                .iter()
+                .flat_map(|stream| stream.scanlines.iter())
+                .map(|scanline| scanline.matched_scanlines.clone())
+                .collect()),
+            _ => panic!("png"),
+        }
+    }
+
+    #[test]
+    fn apng_matched_scanlines_without_data() -> Result<(), Error> {
+        let matched = matched_scanlines(false)?;
+        assert!(matched.iter().any(|matched| !matched.is_empty()));
+        assert_eq!(matched_scanlines(true)?, matched);
+        Ok(())
+    }
+
+    #[test]
+    fn apng_corrupt_height() -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        File::open(path("apng.png"))?.read_to_end(&mut bytes)?;
+        // The IHDR height follows the signature, the chunk length and type, and the width
+        bytes[20..24].copy_from_slice(&0x7fffffffu32.to_be_bytes());
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Png(Box::default()));
+        parse_bytes(
+            &mut stream,
+            &bytes,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+
+        let png = match &stream {
+            Some(CompressedStream::Png(png)) => png,
+            _ => panic!("png"),
+        };
+        let image = &png.streams[0];
+        assert_eq!(0x7fffffff, image.height);
+        assert!(image.scanlines.len() < 1000);
+        assert!(image
+            .warnings
+            .iter()
+            .any(|warning| warning.kind == ErrorKind::SizeMismatch));
+        Ok(())
+    }
+
+    #[test]
+    fn archive_corrupt() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Zip(Box::default()));
+        let mut window = Window::default();
+        parse(
+            &mut stream,
+            &path("archive-corrupt.zip"),
+            &mut window,
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+
+        let zip = match &stream {
+            Some(CompressedStream::Zip(zip)) => zip,
+            _ => panic!("zip"),
+        };
+        assert!(zip.entries.iter().all(|entry| entry.warnings.is_empty()));
+        assert_eq!(
+            vec![1, 0, 0, 0],
+            zip.central_directory
+                .iter()
+                .map(|entry| entry.warnings.len())
+                .collect::<Vec<usize>>()
+        );
+        assert!(zip.warnings.is_empty());
+        Ok(())
+    }
+
+    fn check_prepended_zip(name: &str) -> Result<(), Error> {
+        let mut original = Vec::new();
+        File::open(path(name))?.read_to_end(&mut original)?;
+        // A self-extracting archive: the offsets do not account for the stub
+        let mut bytes = b"MZ".repeat(50);
+        bytes.extend_from_slice(&original);
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Zip(Box::default()));
+        parse_bytes(
+            &mut stream,
+            &bytes,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+
+        let zip = match &stream {
+            Some(CompressedStream::Zip(zip)) => zip,
+            _ => panic!("zip"),
+        };
+        assert!(!zip.entries.is_empty());
+        assert!(zip
+            .entries
+            .iter()
+            .all(|entry| entry.offset >= 100 && entry.warnings.is_empty()));
+        assert_eq!(zip.entries.len(), zip.central_directory.len());
+        assert!(zip
+            .central_directory
+            .iter()
+            .all(|entry| entry.local_entry.is_some() && entry.warnings.is_empty()));
+        assert!(zip.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn archive_prepended() -> Result<(), Error> {
+        check_prepended_zip("archive.zip")?;
+        check_prepended_zip("zip64.zip")
+    }
+
+    #[test]
+    fn deflate64() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Zip(Box::default()));
+        let mut window = Window::default();
+        parse(
+            &mut stream,
+            &path("deflate64.zip"),
+            &mut window,
+            &Settings {
+                bit_offset: 0,
+                data: true,
+                deflate64: false,
+            },
+        )?;
+
+        let zip = match &stream {
+            Some(CompressedStream::Zip(zip)) => zip,
+            _ => panic!("zip"),
+        };
+        let entry = &zip.entries[0];
+        assert!(entry.warnings.is_empty());
+        let tokens = match entry.deflate.as_ref().map(|deflate| &deflate.blocks[1].ext) {
+            Some(Some(DeflateBlockExt::Fixed(fixed))) => fixed.tokens.as_ref().expect("tokens"),
+            _ => panic!("fixed"),
+        };
+        match &tokens[1].v {
+            Token::Window(window) => {
+                assert_eq!(1003, window.length_value);
+                assert_eq!(32774, window.distance_value);
+            }
+            _ => panic!("window"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streams() -> Result<(), Error> {
+        test_file(
+            "streams",
+            ".pdf",
+            Some(CompressedStream::Pdf(Box::default())),
+        )
+    }
+
+    #[test]
+    fn streams_corrupt() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Pdf(Box::default()));
+        let mut window = Window::default();
+        parse(
+            &mut stream,
+            &path("streams-corrupt.pdf"),
+            &mut window,
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+
+        let pdf = match &stream {
+            Some(CompressedStream::Pdf(pdf)) => pdf,
+            _ => panic!("pdf"),
+        };
+        assert_eq!(
+            vec![(3, true), (5, false), (6, false), (7, false)],
+            pdf.objects
+                .iter()
+                .map(|object| (object.number, object.error.is_some()))
+                .collect::<Vec<(u32, bool)>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streams_hostile_predictor() -> Result<(), Error> {
+        let mut pdf = b"%PDF-1.5\n1 0 obj\n<< /Filter /FlateDecode /DecodeParms << /Predictor 12 \
+            /Colors 4000000000 /Columns 4000000000 >> /Length 13 >>\nstream\n"
+            .to_vec();
+        pdf.extend_from_slice(&hex::decode("789ccb48cdc9c90700062c0215").expect("hex"));
+        pdf.extend_from_slice(b"\nendstream\nendobj\n%%EOF\n");
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Pdf(Box::default()));
+        parse_bytes(
+            &mut stream,
+            &pdf,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: true,
+                deflate64: false,
+            },
+        )?;
+
+        let pdf = match &stream {
+            Some(CompressedStream::Pdf(pdf)) => pdf,
+            _ => panic!("pdf"),
+        };
+        assert_eq!(
+            vec![ErrorKind::SizeMismatch],
+            pdf.objects[0]
+                .warnings
+                .iter()
+                .map(|warning| warning.kind)
+                .collect::<Vec<ErrorKind>>()
+        );
+        Ok(())
+    }
+
+    /// Parses a PDF with a single "hello" stream with the given DecodeParms after `prefix`.
+    fn parse_pdf_hello(prefix: &[u8], parms: &str) -> Result<Box<PdfDocument>, Error> {
+        let mut pdf = prefix.to_vec();
+        pdf.extend_from_slice(
+            format!(
+                "%PDF-1.5\n1 0 obj\n<< /Filter /FlateDecode /DecodeParms << {} >> /Length 13 >>\n\
+                 stream\n",
+                parms
+            )
+            .as_bytes(),
+        );
+        pdf.extend_from_slice(&hex::decode("789ccb48cdc9c90700062c0215").expect("hex"));
+        pdf.extend_from_slice(b"\nendstream\nendobj\n%%EOF\n");
+        let mut stream: Option<CompressedStream> = Some(CompressedStream::Pdf(Box::default()));
+        parse_bytes(
+            &mut stream,
+            &pdf,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: prefix.len() * 8,
+                data: true,
+                deflate64: false,
+            },
+        )?;
+        match stream {
+            Some(CompressedStream::Pdf(pdf)) => Ok(pdf),
+            _ => panic!("pdf"),
+        }
+    }
+
+    #[test]
+    fn streams_bit_offset() -> Result<(), Error> {
+        let pdf = parse_pdf_hello(
+            b"9 0 obj\n<< /Filter /FlateDecode /Length 3 >>\nstream\nabc\nendstream\nendobj\n",
+            "",
+        )?;
+        assert!(pdf.warnings.is_empty());
+        assert_eq!(
+            vec![1],
+            pdf.objects
+                .iter()
+                .map(|object| object.number)
+                .collect::<Vec<u32>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streams_tiff_predictor() -> Result<(), Error> {
+        let pdf = parse_pdf_hello(b"", "/Predictor 2 /BitsPerComponent 4")?;
+        let object = &pdf.objects[0];
+        assert_eq!(
+            vec![(
+                ErrorKind::Unsupported,
+                String::from("TIFF Predictor 2 (BitsPerComponent=4)")
+            )],
+            object
+                .warnings
+                .iter()
+                .map(|warning| (warning.kind, warning.msg.clone()))
+                .collect::<Vec<(ErrorKind, String)>>()
+        );
+        assert!(object
+            .predictor
+            .as_ref()
+            .is_some_and(|predictor| predictor.data.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn objects() -> Result<(), Error> {
+        test_file(
+            "objects",
+            ".pack",
+            Some(CompressedStream::Git(Box::default())),
+        )
+    }
+
+    #[test]
+    fn loose() -> Result<(), Error> {
+        test_file("loose", ".git", Some(CompressedStream::Git(Box::default())))
+    }
+
+    #[test]
+    fn session() -> Result<(), Error> {
+        test_file(
+            "session",
+            ".ws",
+            Some(CompressedStream::WebSocket(Box::default())),
+        )
+    }
+
+    #[test]
+    fn frames() -> Result<(), Error> {
+        test_file(
+            "frames",
+            ".d",
+            Some(CompressedStream::WebSocket(Box::new(WebSocketSession {
+                no_context_takeover: true,
+                messages: Vec::new(),
+            }))),
+        )
+    }
+
+    #[test]
+    fn detect() -> Result<(), Error> {
+        for (name, format) in [
+            ("hello.gz", "gzip"),
+            ("bbb.zlib", "zlib"),
+            ("archive.zip", "zip"),
+            ("apng.png", "png"),
+            ("streams.pdf", "pdf"),
+            ("objects.pack", "git"),
+            ("raw.deflate", "raw"),
+        ] {
+            let mut stream: Option<CompressedStream> = None;
+            let mut window = Window::default();
+            // bbb.zlib needs a dictionary, but the format is picked before parsing
+            let _ = parse(
+                &mut stream,
+                &path(name),
+                &mut window,
+                &Settings {
+                    bit_offset: 0,
+                    data: false,
+                    deflate64: false,
+                },
+            );
+            match &stream {
+                Some(CompressedStream::Detected(detected)) => assert_eq!(format, detected.format),
+                _ => panic!("detected"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn detect_unknown() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> = None;
+        let err = parse_bytes(
+            &mut stream,
+            b"neither compressed nor a known container",
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )
+        .expect_err("parse");
+        let msg = match &err {
+            Error::Parse(err) => &err.msg,
+            _ => panic!("parse"),
+        };
+        assert!(
+            msg.ends_with("(parsed as raw deflate: No known signature)"),
+            "{}",
+            msg
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn firmware() -> Result<(), Error> {
+        test_file(
+            "firmware",
+            ".bin",
+            Some(CompressedStream::Scan(Box::new(ScanResult {
+                bit_offsets: false,
+                min_plain_len: 64,
+                hits: Vec::new(),
+            }))),
+        )
+    }
+
+    #[test]
+    fn scan_zero_gap() -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: false,
+            deflate64: false,
+        };
+        let description = r#"{"blocks": [{"type": "fixed", "tokens": [
+            {"type": "text", "v": "abc"},
+            {"type": "match", "length": 258, "distance": 3}
+        ]}]}"#;
+        let deflate = assemble(&serde_json::from_str(description)?, &settings)?;
+        // Zero bytes look like empty non-final stored blocks, but their NLEN does not match
+        let mut bytes = vec![0; 4096];
+        bytes.extend_from_slice(&deflate);
+        let mut stream: Option<CompressedStream> =
+            Some(CompressedStream::Scan(Box::new(ScanResult {
+                bit_offsets: true,
+                min_plain_len: 64,
+                hits: Vec::new(),
+            })));
+        parse_bytes(&mut stream, &bytes, &mut Window::default(), &settings)?;
+
+        let scan = match &stream {
+            Some(CompressedStream::Scan(scan)) => scan,
+            _ => panic!("scan"),
+        };
+        assert_eq!(
+            vec![(4096 * 8, String::from("raw"), 261)],
+            scan.hits
+                .iter()
+                .map(|hit| (hit.offset, hit.format.clone(), hit.plain_len))
+                .collect::<Vec<(usize, String, usize)>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stored_nlen_mismatch() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> =
+            Some(CompressedStream::Raw(DeflateStream::default()));
+        // BFINAL=1, BTYPE=00, LEN=0, NLEN=0
+        parse_bytes(
+            &mut stream,
+            &[0x01, 0x00, 0x00, 0x00, 0x00],
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+
+        let warnings = match &stream {
+            Some(CompressedStream::Raw(deflate)) => match &deflate.blocks[0].ext {
+                Some(DeflateBlockExt::Stored(stored)) => &stored.warnings,
+                _ => panic!("stored"),
+            },
+            _ => panic!("raw"),
+        };
+        assert_eq!(
+            vec![(ErrorKind::StoredLenMismatch, 24)],
+            warnings
+                .iter()
+                .map(|warning| (warning.kind, warning.pos))
+                .collect::<Vec<(ErrorKind, usize)>>()
+        );
+        Ok(())
+    }
+
     #[test]
     fn bbb() -> Result<(), Error> {
         test_zlib("bbb")
     }
+
+    fn zlib_warnings(bytes: &[u8]) -> Result<Vec<(ErrorKind, String)>, Error> {
+        let mut stream: Option<CompressedStream> =
+            Some(CompressedStream::Zlib(ZlibStream::default()));
+        parse_bytes(
+            &mut stream,
+            bytes,
+            &mut Window::default(),
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )?;
+        let zlib = match &stream {
+            Some(CompressedStream::Zlib(zlib)) => zlib,
+            _ => panic!("zlib"),
+        };
+        Ok(zlib
+            .warnings
+            .iter()
+            .map(|warning| (warning.kind, warning.msg.clone()))
+            .collect())
+    }
+
+    /// "hello" compressed with the given CMF, with a valid FCHECK.
+    fn zlib_hello(cmf: u8) -> Vec<u8> {
+        let flg = (31 - u16::from(cmf) * 256 % 31) % 31;
+        let mut bytes = vec![cmf, flg as u8];
+        bytes.extend_from_slice(&hex::decode("cb48cdc9c90700062c0215").expect("hex"));
+        bytes
+    }
+
+    #[test]
+    fn zlib_corrupt() -> Result<(), Error> {
+        assert!(zlib_warnings(&zlib_hello(0x78))?.is_empty());
+        assert_eq!(
+            vec![(ErrorKind::BadHeader, String::from("CM=9"))],
+            zlib_warnings(&zlib_hello(0x79))?
+        );
+        assert_eq!(
+            vec![(ErrorKind::BadHeader, String::from("CINFO=8"))],
+            zlib_warnings(&zlib_hello(0x88))?
+        );
+
+        let mut bytes = zlib_hello(0x78);
+        bytes[1] += 1;
+        assert_eq!(
+            vec![(ErrorKind::BadHeader, String::from("FCHECK (remainder=1)"))],
+            zlib_warnings(&bytes)?
+        );
+
+        let mut bytes = zlib_hello(0x78);
+        let len = bytes.len();
+        bytes[len - 1] ^= 1;
+        assert_eq!(
+            vec![(
+                ErrorKind::ChecksumMismatch,
+                String::from("ADLER32 (computed=0x062c0215)")
+            )],
+            zlib_warnings(&bytes)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bbb_wrong_dictionary() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> =
+            Some(CompressedStream::Zlib(ZlibStream::default()));
+        let mut window = Window::default();
+        window.append_dictionary_from_file(&mut File::open(path("aaa"))?)?;
+        parse(
+            &mut stream,
+            &path("bbb.zlib"),
+            &mut window,
+            &Settings {
+                bit_offset: 0,
+                data: true,
+                deflate64: false,
+            },
+        )?;
+
+        let zlib = match &stream {
+            Some(CompressedStream::Zlib(zlib)) => zlib,
+            _ => panic!("zlib"),
+        };
+        assert_eq!(
+            Some(0x03800131),
+            zlib.dictid.as_ref().map(|dictid| dictid.v)
+        );
+        assert_ne!(zlib.dictid_computed, Some(0x03800131));
+        assert_eq!(
+            vec![
+                (16, ErrorKind::ChecksumMismatch),
+                (80, ErrorKind::ChecksumMismatch)
+            ],
+            zlib.warnings
+                .iter()
+                .map(|warning| (warning.pos, warning.kind))
+                .collect::<Vec<(usize, ErrorKind)>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hello_raw() -> Result<(), Error> {
+        let mut stream: Option<CompressedStream> =
+            Some(CompressedStream::Raw(DeflateStream::default()));
+        let mut window = Window::default();
+        let err = parse(
+            &mut stream,
+            &path("hello.gz"),
+            &mut window,
+            &Settings {
+                bit_offset: 0,
+                data: false,
+                deflate64: false,
+            },
+        )
+        .expect_err("parse");
+
+        // The gzip magic reads as BFINAL=1 BTYPE=3
+        assert_eq!(ErrorKind::BadHeader, err.kind());
+        assert_eq!("BTYPE=3 at bit 3", err.to_string());
+        let context = match &err {
+            Error::Parse(err) => err.context.as_ref().expect("context"),
+            _ => panic!("parse"),
+        };
+        assert_eq!(
+            (Some(0), Some(0), Some(3), Some("header.btype"), Some(0)),
+            (
+                context.block,
+                context.block_start,
+                context.btype,
+                context.field.as_deref(),
+                context.plain_pos
+            )
+        );
+        let err: Box<dyn std::error::Error> = Box::new(err);
+        assert!(err.source().is_none());
+        Ok(())
+    }
+
+    fn test_encode(name: &Path, mut stream: Option<CompressedStream>) -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        parse(&mut stream, name, &mut Window::default(), &settings)?;
+        let mut bytes = Vec::new();
+        File::open(name)?.read_to_end(&mut bytes)?;
+        for deflate in get_deflate_streams(stream.as_ref().expect("stream")) {
+            let start = deflate.blocks[0]
+                .header
+                .bfinal
+                .as_ref()
+                .expect("bfinal")
+                .start;
+            let end = deflate.padding.as_ref().expect("padding").end;
+            assert_eq!(0, start % 8);
+            assert_eq!(
+                hex::encode(&bytes[start / 8..end / 8]),
+                hex::encode(encode_deflate(deflate, &settings)?),
+                "{}",
+                name.display()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<(), Error> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(path(""))? {
+            let entry = entry?.path();
+            if entry.extension().is_some_and(|extension| extension == "gz") {
+                names.push(entry);
+            }
+        }
+        assert!(!names.is_empty());
+        for name in names {
+            test_encode(&name, None)?;
+        }
+        // The gzip fixtures have no dynamic blocks
+        test_encode(
+            &path("objects.pack"),
+            Some(CompressedStream::Git(Box::default())),
+        )
+    }
+
+    fn token_values(deflate: &DeflateStream) -> Vec<(u32, u32)> {
+        let mut values = Vec::new();
+        for block in &deflate.blocks {
+            let tokens = match &block.ext {
+                Some(DeflateBlockExt::Fixed(fixed)) => &fixed.tokens,
+                Some(DeflateBlockExt::Dynamic(dynamic)) => &dynamic.tokens,
+                _ => continue,
+            };
+            values.extend(tokens.iter().flatten().map(|token| match &token.v {
+                Token::Literal(literal) => (u32::from(literal.v), 0),
+                Token::Eob(_) => (256, 0),
+                Token::Window(window) => (window.length_value, window.distance_value),
+            }));
+        }
+        values
+    }
+
+    #[test]
+    fn encode_edited_dht() -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        let mut stream = Some(CompressedStream::Git(Box::default()));
+        parse(
+            &mut stream,
+            &path("objects.pack"),
+            &mut Window::default(),
+            &settings,
+        )?;
+        let mut deflate = get_deflate_streams(stream.as_ref().expect("stream"))
+            .into_iter()
+            .find(|deflate| matches!(deflate.blocks[0].ext, Some(DeflateBlockExt::Dynamic(_))))
+            .expect("dynamic");
+        let original = encode_deflate(deflate, &settings)?;
+        let expected = token_values(deflate);
+
+        // Swap the lengths of two literals, so that the table no longer matches its original
+        // run-length encoding
+        let mut edited = serde_json::from_value::<DeflateStream>(serde_json::to_value(deflate)?)?;
+        let dht = match &mut edited.blocks[0].ext {
+            Some(DeflateBlockExt::Dynamic(dynamic)) => dynamic.dht.as_mut().expect("dht"),
+            _ => unreachable!(),
+        };
+        let hlits = dht.hlits.as_mut().expect("hlits");
+        let (i, j) = (0..256)
+            .flat_map(|i| (i + 1..256).map(move |j| (i, j)))
+            .find(|(i, j)| hlits[*i].v != 0 && hlits[*j].v != 0 && hlits[*i].v != hlits[*j].v)
+            .expect("lengths");
+        let v = hlits[i].v;
+        hlits[i].v = hlits[j].v;
+        hlits[j].v = v;
+        let bytes = encode_deflate(&edited, &settings)?;
+        assert_ne!(original, bytes);
+
+        let mut reparsed = Some(CompressedStream::Raw(DeflateStream::default()));
+        parse_bytes(&mut reparsed, &bytes, &mut Window::default(), &settings)?;
+        deflate = match &reparsed {
+            Some(CompressedStream::Raw(deflate)) => deflate,
+            _ => panic!("raw"),
+        };
+        assert_eq!(expected, token_values(deflate));
+        Ok(())
+    }
+
+    fn parse_assembled(
+        description: &str,
+        stream: &mut Option<CompressedStream>,
+    ) -> Result<Vec<u8>, Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        let bytes = assemble(&serde_json::from_str(description)?, &settings)?;
+        *stream = Some(CompressedStream::Raw(DeflateStream::default()));
+        parse_bytes(stream, &bytes, &mut Window::default(), &settings)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn assemble_roundtrip() -> Result<(), Error> {
+        let mut stream = None;
+        let bytes = parse_assembled(
+            r#"{"blocks": [
+                {"type": "stored", "data": "6869"},
+                {"type": "fixed", "tokens": [
+                    {"type": "literal", "v": 97},
+                    {"type": "match", "length": 3, "distance": 1}
+                ]},
+                {"type": "dynamic", "tokens": []},
+                {"type": "dynamic", "tokens": [
+                    {"type": "text", "v": "abc"},
+                    {"type": "match", "length": 258, "distance": 3}
+                ]}
+            ]}"#,
+            &mut stream,
+        )?;
+        let mut plain = Vec::new();
+        {
+            let mut extract: File = tempfile::tempfile()?;
+            write_data(&mut extract, &stream)?;
+            extract.seek(io::SeekFrom::Start(0))?;
+            extract.read_to_end(&mut plain)?;
+        }
+        assert_eq!([b"hiaaaa".as_slice(), &b"abc".repeat(87)].concat(), plain);
+
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        let deflate = match &stream {
+            Some(CompressedStream::Raw(deflate)) => deflate,
+            _ => panic!("raw"),
+        };
+        let bfinals = deflate
+            .blocks
+            .iter()
+            .map(|block| block.header.bfinal.as_ref().expect("bfinal").v)
+            .collect::<Vec<u8>>();
+        assert_eq!(vec![0, 0, 0, 1], bfinals);
+        assert_eq!(bytes, encode_deflate(deflate, &settings)?);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_invalid_lengths() -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: false,
+        };
+        for (field, lens) in [
+            ("hlits", vec![20; 257]),
+            ("hlits", vec![16; 257]),
+            ("hdists", vec![16; 30]),
+            ("hclens", vec![8; 19]),
+        ] {
+            let description = format!(
+                r#"{{"blocks": [{{"type": "dynamic", "{}": {:?}, "tokens": []}}]}}"#,
+                field, lens
+            );
+            let err =
+                assemble(&serde_json::from_str(&description)?, &settings).expect_err("assemble");
+            assert_eq!(ErrorKind::Encode, err.kind());
+            assert!(err.to_string().contains(field), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_hclens_without_repeats() -> Result<(), Error> {
+        let mut stream = None;
+        // Only the code lengths 0 and 2 have codes, so the zeros cannot be repeated
+        let mut hclens = vec![0; 19];
+        hclens[0] = 1;
+        hclens[2] = 1;
+        let description = format!(
+            r#"{{"blocks": [{{"type": "dynamic", "hclens": {:?}, "tokens": [
+                {{"type": "text", "v": "abc"}}
+            ]}}]}}"#,
+            hclens
+        );
+        parse_assembled(&description, &mut stream)?;
+        let deflate = match &stream {
+            Some(CompressedStream::Raw(deflate)) => deflate,
+            _ => panic!("raw"),
+        };
+        assert_eq!(
+            vec![(97, 0), (98, 0), (99, 0), (256, 0)],
+            token_values(deflate)
+        );
+        let dht = match &deflate.blocks[0].ext {
+            Some(DeflateBlockExt::Dynamic(dynamic)) => dynamic.dht.as_ref().expect("dht"),
+            _ => panic!("dynamic"),
+        };
+        assert!(dht
+            .hlits_rle
+            .iter()
+            .flatten()
+            .all(|code| code.symbol.v < 16));
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_deflate64_lengths() -> Result<(), Error> {
+        let settings = Settings {
+            bit_offset: 0,
+            data: true,
+            deflate64: true,
+        };
+        let description = r#"{"blocks": [{"type": "fixed", "tokens": [
+            {"type": "text", "v": "a"},
+            {"type": "match", "length": 3, "distance": 1},
+            {"type": "match", "length": 10, "distance": 1},
+            {"type": "match", "length": 258, "distance": 1},
+            {"type": "match", "length": 259, "distance": 1},
+            {"type": "match", "length": 65538, "distance": 1}
+        ]}]}"#;
+        let bytes = assemble(&serde_json::from_str(description)?, &settings)?;
+        let mut stream = Some(CompressedStream::Raw(DeflateStream::default()));
+        parse_bytes(&mut stream, &bytes, &mut Window::default(), &settings)?;
+
+        let deflate = match &stream {
+            Some(CompressedStream::Raw(deflate)) => deflate,
+            _ => panic!("raw"),
+        };
+        let tokens = match &deflate.blocks[0].ext {
+            Some(DeflateBlockExt::Fixed(fixed)) => fixed.tokens.as_ref().expect("tokens"),
+            _ => panic!("fixed"),
+        };
+        let lengths = tokens
+            .iter()
+            .filter_map(|token| match &token.v {
+                Token::Window(window) => Some((window.length.v, window.length_value)),
+                _ => None,
+            })
+            .collect::<Vec<(u16, u32)>>();
+        // Only the lengths that no other code has take the 16 extra bits of code 285
+        assert_eq!(
+            vec![(257, 3), (264, 10), (284, 258), (285, 259), (285, 65538)],
+            lengths
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hdists_context() -> Result<(), Error> {
+        let mut stream = None;
+        let err = parse_assembled(
+            r#"{"blocks": [{"type": "dynamic", "hdists": [1, 1, 1], "tokens": [
+                {"type": "text", "v": "abc"}
+            ]}]}"#,
+            &mut stream,
+        )
+        .expect_err("parse");
+        assert_eq!(ErrorKind::InvalidHuffmanTable, err.kind());
+        let field = match &err {
+            Error::Parse(err) => err.context.as_ref().and_then(|x| x.field.clone()),
+            _ => panic!("parse"),
+        };
+        assert_eq!(Some(String::from("dht.hdists")), field);
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_invalid() -> Result<(), Error> {
+        let mut stream = None;
+        parse_assembled(
+            r#"{"blocks": [{"type": "stored", "data": "6869", "nlen": 0}]}"#,
+            &mut stream,
+        )?;
+        let nlen = match &stream {
+            Some(CompressedStream::Raw(deflate)) => match &deflate.blocks[0].ext {
+                Some(DeflateBlockExt::Stored(stored)) => stored.nlen.as_ref().expect("nlen").v,
+                _ => panic!("stored"),
+            },
+            _ => panic!("raw"),
+        };
+        assert_eq!(0, nlen);
+
+        let err = parse_assembled(
+            r#"{"blocks": [{"type": "fixed", "tokens": [
+                {"type": "literal", "v": 97},
+                {"type": "match", "length": 3, "distance": 2}
+            ]}]}"#,
+            &mut stream,
+        )
+        .expect_err("parse");
+        assert_eq!(ErrorKind::DistanceTooFar, err.kind());
+
+        // Four codes of length 1
+        let hlits = [vec![0; 97], vec![1; 3], vec![0; 156], vec![1]].concat();
+        let err = parse_assembled(
+            &format!(
+                r#"{{"blocks": [{{"type": "dynamic", "hlits": {:?}, "tokens": [
+                    {{"type": "text", "v": "abc"}}
+                ]}}]}}"#,
+                hlits
+            ),
+            &mut stream,
+        )
+        .expect_err("parse");
+        assert_eq!(ErrorKind::InvalidHuffmanTable, err.kind());
+        Ok(())
+    }
 }